@@ -36,7 +36,7 @@ impl From<GuildMemberUpdateEvent> for UserData {
         let GuildMemberUpdateEvent { nick, user, .. } = e;
         Self {
             id: user.id,
-            name: nick.unwrap_or_else(|| user.name),
+            name: nick.unwrap_or(user.name),
         }
     }
 }
@@ -2,21 +2,28 @@
 
 mod channel;
 mod guild;
+mod metrics;
 
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use async_trait::async_trait;
 use parking_lot::RwLock;
 use rendezvous_common::{
     anyhow,
     futures::prelude::*,
+    outbox::{self, Outbox, Poster},
     proto::{
-        bouncer_service_client::BouncerServiceClient, event, ClientType, Event, Header,
-        MessageCreated, UserRenamed,
+        bouncer_service_client::BouncerServiceClient, event,
+        history_service_client::HistoryServiceClient, BouncerStatus, ClientType, DeliveryAck,
+        Event, MembershipChange, MembershipChanged, MessageCreated, SubscribeRequest, SystemNotice,
+        UserRenamed,
     },
+    search, shutdown,
+    subscription::Subscription,
     tokio,
     tonic::transport,
-    tracing::{self, debug, error, info, info_span, warn},
+    tracing::{self, debug, error, info, info_span, instrument, warn, Instrument},
 };
 use serenity::{
     http::Http,
@@ -24,7 +31,7 @@ use serenity::{
         self,
         channel::{ChannelType, GuildChannel, Message, MessageType},
         guild::{Guild, Member},
-        id::GuildId,
+        id::{ChannelId, GuildId},
     },
     prelude::*,
 };
@@ -36,29 +43,106 @@ use crate::{
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing::init()?;
+    let _guard = tracing::init(env!("CARGO_PKG_NAME"))?;
 
-    let mut rpc_client = BouncerServiceClient::connect("http://[::1]:49252").await?;
+    // Connected when needed, so that Discord is relayed while the server is down as well. The
+    // server is at `RENDEZVOUS_DISCORD_SERVER`.
+    let server = std::env::var("RENDEZVOUS_DISCORD_SERVER")
+        .unwrap_or_else(|_| rendezvous_common::DEFAULT_SERVER_URL.to_owned());
+    let rpc_channel = rendezvous_common::endpoint(&server)?.connect_lazy();
+    let rpc_client = BouncerServiceClient::new(rpc_channel.clone());
+    let history_client = HistoryServiceClient::new(rpc_channel);
+
+    // Events the server couldn't take, posted again once it's back; the file and the number
+    // of events to keep at most can be set with `RENDEZVOUS_DISCORD_OUTBOX` and
+    // `RENDEZVOUS_DISCORD_OUTBOX_CAPACITY`.
+    let outbox = Outbox::open(
+        std::env::var("RENDEZVOUS_DISCORD_OUTBOX").unwrap_or_else(|_| "outbox-discord.bin".into()),
+        match std::env::var("RENDEZVOUS_DISCORD_OUTBOX_CAPACITY") {
+            Ok(capacity) => capacity.parse()?,
+            Err(_) => outbox::DEFAULT_CAPACITY,
+        },
+    )?;
+    let poster = Arc::new(Poster::new(rpc_client.clone(), outbox));
+    {
+        let poster = Arc::clone(&poster);
+        tokio::spawn(async move {
+            if let Err(e) = poster.retry(outbox::RETRY_INTERVAL).await {
+                error!("failed to keep the outbox: {:?}", e);
+            }
+        });
+    }
 
     let token = std::env::var("RENDEZVOUS_DISCORD_BOT_TOKEN")?;
+    let notices = StatusNotices::from_env();
+    if let Ok(addr) = std::env::var("RENDEZVOUS_DISCORD_METRICS_ADDR") {
+        let addr = addr.parse()?;
+        tokio::spawn(async move {
+            if let Err(e) = rendezvous_common::metrics::serve(addr).await {
+                error!("metrics endpoint failed: {:?}", e);
+            }
+        });
+    }
 
-    let handler = Handler::new(rpc_client.clone());
+    let handler = Handler::new(Arc::clone(&poster), history_client);
     let channels = Arc::clone(&handler.channels);
     let mut discord_client = Client::builder(&token).event_handler(handler).await?;
 
     let http = Arc::clone(&discord_client.cache_and_http.http);
+    let mut ack_client = rpc_client.clone();
 
-    let mut resp = rpc_client
-        .subscribe(Header {
-            client_type: ClientType::Discord.into(),
-        })
-        .await?;
+    let mut subscription = Subscription::new(
+        rpc_client.clone(),
+        SubscribeRequest::new(ClientType::Discord),
+    );
 
+    let shard_manager = Arc::clone(&discord_client.shard_manager);
     let handle_rpc_stream = async move {
-        let stream = resp.get_mut();
-        while let Some(m) = stream.try_next().await? {
-            handle_ipc_event(&http, &channels, m).await?;
+        let signal = shutdown::signal();
+        tokio::pin!(signal);
+        loop {
+            // Only stops between events, so the one being relayed is sent to Discord first.
+            // The server going away is waited out.
+            let m = tokio::select! {
+                m = subscription.next() => m,
+                signal = &mut signal => {
+                    info!("received {}, shutting down", signal?);
+                    break;
+                }
+            };
+            if let Some(event::Body::Heartbeat(heartbeat)) = m.body {
+                if let Err(status) = ack_client.ack_heartbeat(heartbeat).await {
+                    warn!("failed to answer a heartbeat: {}", status);
+                }
+                continue;
+            }
+            // Already relayed when it happened, or coming back around a loop.
+            if m.replayed || !poster.is_new(&m) {
+                continue;
+            }
+            let wants_ack = m.wants_ack();
+            let event_id = m.id.clone();
+            let span = info_span!("relay_to_discord", event_id = %event_id);
+            tracing::set_parent(&span, &m.trace_context);
+            let result = handle_ipc_event(&http, &channels, &notices, m)
+                .instrument(span.clone())
+                .await;
+            if wants_ack {
+                let ack = DeliveryAck::new(
+                    ClientType::Discord,
+                    event_id.clone(),
+                    result.as_ref().map(|_| ()),
+                );
+                if let Err(status) = ack_client.ack(ack).instrument(span).await {
+                    warn!("failed to report the delivery of {}: {}", event_id, status);
+                }
+            }
+            if let Err(e) = result {
+                metrics::SEND_FAILURES.with_label_values(&["discord"]).inc();
+                error!("failed to relay event: {:?}", e);
+            }
         }
+        shard_manager.lock().await.shutdown_all().await;
         Ok::<_, anyhow::Error>(())
     };
 
@@ -71,9 +155,40 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Notices posted into the bridged channels when another bouncer goes down or comes back.
+///
+/// The bridged channels are listed by name, separated with commas, in
+/// `RENDEZVOUS_DISCORD_BRIDGED_CHANNELS`, and are every text channel the bot sees when it's
+/// unset. The messages can be set with `RENDEZVOUS_DISCORD_NOTICE_UP` and
+/// `RENDEZVOUS_DISCORD_NOTICE_DOWN`; `{bridge}` is replaced with the name of the bouncer.
+struct StatusNotices {
+    /// `None` for every channel.
+    channels: Option<Vec<String>>,
+    up: Option<String>,
+    down: Option<String>,
+}
+
+impl StatusNotices {
+    fn from_env() -> Self {
+        let channels = std::env::var("RENDEZVOUS_DISCORD_BRIDGED_CHANNELS").ok();
+        StatusNotices {
+            channels: channels.map(|channels| {
+                channels
+                    .split(',')
+                    .map(|name| name.trim().trim_start_matches('#').to_owned())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            }),
+            up: std::env::var("RENDEZVOUS_DISCORD_NOTICE_UP").ok(),
+            down: std::env::var("RENDEZVOUS_DISCORD_NOTICE_DOWN").ok(),
+        }
+    }
+}
+
 async fn handle_ipc_event(
     http: &Http,
     channels: &RwLock<ChannelList>,
+    notices: &StatusNotices,
     e: Event,
 ) -> anyhow::Result<()> {
     match e.body {
@@ -83,21 +198,45 @@ async fn handle_ipc_event(
             content,
             ..
         })) => {
-            if !channel.starts_with("#") {
-                return Ok(());
+            let channel_id = match find_channel(channels, &channel) {
+                Some(id) => id,
+                None => return Ok(()),
+            };
+            channel_id
+                .send_message(http, |m| m.content(format!("<{}> {}", nickname, content)))
+                .await?;
+            metrics::MESSAGES_RELAYED
+                .with_label_values(&["to_discord"])
+                .inc();
+        }
+        Some(event::Body::BouncerStatusChanged(status)) => {
+            let template = match status.status() {
+                BouncerStatus::Up => notices.up.as_deref(),
+                BouncerStatus::Down => notices.down.as_deref(),
+                BouncerStatus::Unknown => return Ok(()),
+            };
+            let notice = status.notice(template);
+            let channel_ids: Vec<_> = {
+                let channels = channels.read();
+                match &notices.channels {
+                    Some(names) => names
+                        .iter()
+                        .filter_map(|name| channels.get_by_name(name).map(|ch| ch.id()))
+                        .collect(),
+                    // Not into direct messages.
+                    None => channels
+                        .iter()
+                        .filter_map(|ch| ch.as_guild().map(|ch| ch.id))
+                        .collect(),
+                }
+            };
+            for channel_id in channel_ids {
+                channel_id.say(http, &notice).await?;
             }
-            let channels = channels.read();
-            debug!(
-                "channels: {:?}",
-                channels.iter().map(|ch| ch.name()).collect::<Vec<_>>()
-            );
-            if let Some(ch) = channels.get_by_name(&channel[1..]) {
-                debug!("{:?}", ch);
-                ch.id()
-                    .send_message(http, |m| m.content(format!("<{}> {}", nickname, content)))
-                    .await?;
-            } else {
-                warn!("unknown channel: {:?}", channel);
+        }
+        Some(event::Body::SystemNotice(SystemNotice { channel, content })) => {
+            if let Some(channel_id) = find_channel(channels, &channel) {
+                channel_id.say(http, &content).await?;
             }
         }
         _ => {}
@@ -105,20 +244,58 @@ async fn handle_ipc_event(
     Ok(())
 }
 
+/// Looks up a bridged `#channel` by name. Returns `None` for names without `#`, and for
+/// unknown channels with a warning, so that the events for them are skipped.
+fn find_channel(channels: &RwLock<ChannelList>, channel: &str) -> Option<ChannelId> {
+    if !channel.starts_with("#") {
+        return None;
+    }
+    let channels = channels.read();
+    debug!(
+        "channels: {:?}",
+        channels.iter().map(|ch| ch.name()).collect::<Vec<_>>()
+    );
+    match channels.get_by_name(&channel[1..]) {
+        Some(ch) => {
+            debug!("{:?}", ch);
+            Some(ch.id())
+        }
+        None => {
+            warn!("unknown channel: {:?}", channel);
+            None
+        }
+    }
+}
+
+/// Joining or leaving a Discord server, rather than a channel.
+fn membership_changed(nickname: String, change: MembershipChange) -> Event {
+    Event::new(
+        ClientType::Discord,
+        event::Body::MembershipChanged(MembershipChanged {
+            nickname,
+            channel: "".to_owned(),
+            change: change.into(),
+            reason: "".to_owned(),
+        }),
+    )
+}
+
 struct Handler {
     guilds: RwLock<GuildMap>,
     channels: Arc<RwLock<ChannelList>>,
     current_user: RwLock<Option<model::user::CurrentUser>>,
-    rpc_client: BouncerServiceClient<transport::Channel>,
+    poster: Arc<Poster>,
+    history_client: HistoryServiceClient<transport::Channel>,
 }
 
 impl Handler {
-    fn new(rpc_client: BouncerServiceClient<transport::Channel>) -> Self {
+    fn new(poster: Arc<Poster>, history_client: HistoryServiceClient<transport::Channel>) -> Self {
         Handler {
             guilds: Default::default(),
             channels: Default::default(),
             current_user: Default::default(),
-            rpc_client,
+            poster,
+            history_client,
         }
     }
 
@@ -127,7 +304,7 @@ impl Handler {
         lock.insert(guild.id, guild.into())
     }
 
-    fn register_guild_channel<'a>(&'a self, channel: &GuildChannel) -> bool {
+    fn register_guild_channel(&self, channel: &GuildChannel) -> bool {
         if channel.kind != ChannelType::Text {
             return false;
         }
@@ -136,10 +313,38 @@ impl Handler {
             .get_or_insert_with(channel.id, || Channel::Guild(channel.clone()));
         true
     }
+
+    /// Rejected events are dropped; if the server is unreachable, they're kept for later
+    /// instead.
+    #[instrument(skip(self, event))]
+    async fn post(&self, event: Event) {
+        match self.poster.post(event).await {
+            Ok(Some(resp)) => {
+                metrics::MESSAGES_RELAYED
+                    .with_label_values(&["to_server"])
+                    .inc();
+                for d in resp.failures() {
+                    warn!(
+                        "not relayed to {:?}: {:?} {}",
+                        d.destination(),
+                        d.status(),
+                        d.reason
+                    );
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                metrics::SEND_FAILURES.with_label_values(&["server"]).inc();
+                error!("failed to send event: {:?}", e);
+            }
+        }
+    }
 }
 
 #[async_trait]
 impl EventHandler for Handler {
+    // `GuildStatus` goes away with serenity 0.11, which isn't out yet.
+    #[allow(deprecated)]
     async fn ready(
         &self,
         _ctx: Context,
@@ -151,7 +356,9 @@ impl EventHandler for Handler {
         }: model::gateway::Ready,
     ) {
         use model::guild::GuildStatus::*;
-        *self.current_user.write() = Some(user);
+        if self.current_user.write().replace(user).is_some() {
+            metrics::RECONNECTS.inc();
+        }
         self.guilds
             .write()
             .extend(guilds.into_iter().filter_map(|g| match g {
@@ -160,15 +367,19 @@ impl EventHandler for Handler {
             }));
         self.channels.write().extend(
             private_channels
-                .into_iter()
-                .filter_map(|(_, ch)| Channel::from_discord(ch)),
+                .into_values()
+                .filter_map(Channel::from_discord),
         );
     }
 
+    async fn resume(&self, _ctx: Context, _: model::event::ResumedEvent) {
+        metrics::RECONNECTS.inc();
+    }
+
     async fn guild_create(&self, _ctx: Context, guild: Guild) {
         let mut new_channels = vec![];
         for chan in guild.channels.values() {
-            if self.register_guild_channel(&chan) {
+            if self.register_guild_channel(chan) {
                 new_channels.push(chan.name.clone());
             }
         }
@@ -176,10 +387,13 @@ impl EventHandler for Handler {
     }
 
     async fn guild_member_addition(&self, _ctx: Context, guild_id: GuildId, new_member: Member) {
+        let member = UserData::from(new_member);
+        let nickname = member.name.clone();
         if let Some(g) = self.guilds.write().get_mut(&guild_id) {
-            let id = new_member.user.id;
-            g.members.insert(id, new_member.into());
+            g.members.insert(member.id, member);
         }
+        self.post(membership_changed(nickname, MembershipChange::Joined))
+            .await;
     }
 
     async fn guild_member_removal(
@@ -188,9 +402,14 @@ impl EventHandler for Handler {
         guild_id: GuildId,
         user: model::user::User,
     ) {
-        if let Some(g) = self.guilds.write().get_mut(&guild_id) {
-            g.members.remove(&user.id);
-        }
+        let removed = self
+            .guilds
+            .write()
+            .get_mut(&guild_id)
+            .and_then(|g| g.members.remove(&user.id));
+        let nickname = removed.map(|m| m.name).unwrap_or(user.name);
+        self.post(membership_changed(nickname, MembershipChange::Left))
+            .await;
     }
 
     async fn guild_member_update(&self, _ctx: Context, new: model::event::GuildMemberUpdateEvent) {
@@ -204,26 +423,22 @@ impl EventHandler for Handler {
             .and_then(|g| g.members.get_mut(&new.id))
         {
             if m.name != new.name {
-                event = Some(Event {
-                    header: Some(Header {
-                        client_type: ClientType::Discord.into(),
-                    }),
-                    body: Some(event::Body::UserRenamed(UserRenamed {
+                event = Some(Event::new(
+                    ClientType::Discord,
+                    event::Body::UserRenamed(UserRenamed {
                         old: m.name.clone(),
                         new: new.name.clone(),
-                    })),
-                });
+                    }),
+                ));
             }
             *m = new;
         }
         if let Some(req) = event {
-            if let Err(e) = self.rpc_client.clone().post(req).await {
-                error!("failed to send event: {:?}", e);
-            }
+            self.post(req).await;
         }
     }
 
-    async fn message(&self, _ctx: Context, new_message: Message) {
+    async fn message(&self, ctx: Context, new_message: Message) {
         let span = info_span!("message");
         let _enter = span.enter();
         info!("entered");
@@ -238,26 +453,36 @@ impl EventHandler for Handler {
             return;
         }
 
+        let reply = search::respond(&mut self.history_client.clone(), &new_message.content).await;
+        if let Some(reply) = reply {
+            if let Err(e) = new_message
+                .channel_id
+                .say(&ctx.http, reply.join("\n"))
+                .await
+            {
+                error!("failed to answer the search: {:?}", e);
+            }
+        }
+
         let mut event = None;
         if let Some(ch) = self.channels.read().get_by_id(new_message.channel_id) {
-            event = Some(Event {
-                header: Some(Header {
-                    client_type: ClientType::Discord.into(),
-                }),
-                body: Some(event::Body::MessageCreated(MessageCreated {
+            let mut e = Event::new(
+                ClientType::Discord,
+                event::Body::MessageCreated(MessageCreated {
                     nickname: author_name(&self.guilds.read(), &new_message).to_owned(),
                     channel: format!("#{}", ch.name()),
                     content: new_message.content,
                     origin: "".to_owned(),
-                })),
-            });
+                }),
+            );
+            e.sent_at = Some(SystemTime::from(new_message.timestamp).into());
+            e.origin_id = format!("discord:{}", new_message.id);
+            event = Some(e);
         } else {
             info!("channel not found: {}", new_message.channel_id);
         }
         if let Some(req) = event {
-            if let Err(e) = self.rpc_client.clone().post(req).await {
-                error!("failed to send event: {:?}", e);
-            }
+            self.post(req).await;
         }
     }
 }
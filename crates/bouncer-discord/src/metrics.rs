@@ -0,0 +1,31 @@
+use rendezvous_common::metrics::{
+    register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec, Lazy,
+};
+
+/// By direction, either `to_server` or `to_discord`.
+pub static MESSAGES_RELAYED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "rendezvous_discord_messages_relayed_total",
+        "Messages relayed by the Discord bouncer.",
+        &["direction"]
+    )
+    .unwrap()
+});
+
+/// By destination, either `server` or `discord`.
+pub static SEND_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "rendezvous_discord_send_failures_total",
+        "Messages the Discord bouncer failed to relay.",
+        &["destination"]
+    )
+    .unwrap()
+});
+
+pub static RECONNECTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "rendezvous_discord_reconnects_total",
+        "Times the Discord gateway connection was resumed or re-established."
+    )
+    .unwrap()
+});
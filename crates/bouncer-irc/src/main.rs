@@ -1,103 +1,341 @@
 #![warn(clippy::all)]
 
+mod metrics;
+
 use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use irc::client::{prelude::*, ClientStream};
+use irc::{
+    client::{prelude::*, ClientStream},
+    proto::message::Tag,
+};
 
 use rendezvous_common::{
     anyhow,
+    chrono::DateTime,
     futures::prelude::*,
+    // ipc,
+    outbox::{self, Outbox, Poster},
     proto::{
-        bouncer_service_client::BouncerServiceClient, event, ClientType, Event, Header,
-        MessageCreated,
+        bouncer_service_client::BouncerServiceClient, event,
+        history_service_client::HistoryServiceClient, BouncerStatus, BouncerStatusChanged,
+        ClientType, DeliveryAck, Event, MembershipChange, MembershipChanged, MessageCreated,
+        SubscribeRequest,
     },
-    // ipc,
+    search,
+    shutdown,
+    subscription::Subscription,
     tokio,
-    tonic::{transport::Channel, Response, Streaming},
-    tracing::{self, info, instrument},
+    tonic::transport::Channel,
+    tracing::{self, error, info, info_span, instrument, warn, Instrument},
 };
 
+const QUIT_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing::init()?;
+    let _guard = tracing::init(env!("CARGO_PKG_NAME"))?;
 
-    let mut irc_client = Client::new("config.toml").await?;
+    let config = Config::load("config.toml")?;
+    let notices = StatusNotices::from_config(&config);
+    let quit_message = config
+        .get_option("quit_message")
+        .unwrap_or("Rendezvous is shutting down")
+        .to_owned();
+    // Events the server couldn't take, posted again once it's back; the file and the number
+    // of events to keep at most can be set with `outbox` and `outbox_capacity` in `[options]`.
+    let outbox = Outbox::open(
+        config.get_option("outbox").unwrap_or("outbox-irc.bin"),
+        match config.get_option("outbox_capacity") {
+            Some(capacity) => capacity.parse()?,
+            None => outbox::DEFAULT_CAPACITY,
+        },
+    )?;
+    // `server` in `[options]`.
+    let server = config
+        .get_option("server")
+        .unwrap_or(rendezvous_common::DEFAULT_SERVER_URL)
+        .to_owned();
+    if let Some(addr) = config.get_option("metrics_addr") {
+        let addr: SocketAddr = addr.parse()?;
+        tokio::spawn(async move {
+            if let Err(e) = rendezvous_common::metrics::serve(addr).await {
+                error!("metrics endpoint failed: {:?}", e);
+            }
+        });
+    }
+    let mut irc_client = Client::from_config(config).await?;
+    // Timestamps the messages with the time the IRC server received them, and gives them ids.
+    irc_client.send_cap_req(&[Capability::ServerTime, Capability::Custom("message-tags")])?;
     irc_client.identify()?;
     info!("connected");
 
-    let mut client = BouncerServiceClient::connect("http://[::1]:49252").await?;
+    // Connected when needed, so that IRC is relayed while the server is down as well.
+    let channel = rendezvous_common::endpoint(&server)?.connect_lazy();
+    let client = BouncerServiceClient::new(channel.clone());
+    let history = HistoryServiceClient::new(channel);
+    let poster = Arc::new(Poster::new(client.clone(), outbox));
+    let retry = {
+        let poster = Arc::clone(&poster);
+        async move { poster.retry(outbox::RETRY_INTERVAL).await }
+    };
 
-    let resp = client
-        .subscribe(Header {
-            client_type: ClientType::Irc.into(),
-        })
-        .await?;
+    let subscription = Subscription::new(client.clone(), SubscribeRequest::new(ClientType::Irc));
 
-    tokio::try_join!(
-        handle_irc_stream(irc_client.stream()?, client),
-        handle_rpc_stream(resp, irc_client.sender()),
-    )?;
-    Ok(())
+    let sender = irc_client.sender();
+    let irc = handle_irc_stream(
+        irc_client.stream()?,
+        sender.clone(),
+        Arc::clone(&poster),
+        history,
+    );
+    let rpc = handle_rpc_stream(
+        subscription,
+        sender.clone(),
+        client,
+        Arc::clone(&poster),
+        notices,
+    );
+    tokio::pin!(irc, rpc, retry);
+    tokio::select! {
+        result = &mut irc => return result,
+        result = &mut rpc => return result,
+        result = &mut retry => return result,
+        signal = shutdown::signal() => info!("received {}, quitting", signal?),
+    }
+
+    // Stops relaying events from the server, but keeps reading from IRC so that the queued
+    // messages and the QUIT get flushed, until the IRC server closes the connection.
+    sender.send_quit(&quit_message)?;
+    match tokio::time::timeout(QUIT_TIMEOUT, irc).await {
+        Ok(result) => result,
+        Err(_) => anyhow::bail!("the IRC server didn't close the connection in time"),
+    }
 }
 
 #[instrument]
 async fn handle_irc_stream(
     mut irc_stream: ClientStream,
-    mut client: BouncerServiceClient<Channel>,
+    sender: Sender,
+    poster: Arc<Poster>,
+    mut history: HistoryServiceClient<Channel>,
 ) -> anyhow::Result<()> {
     while let Some(irc_msg) = irc_stream.try_next().await? {
-        let nickname = irc_msg.source_nickname().unwrap_or("").into();
-        match irc_msg.command {
+        let nickname = irc_msg.source_nickname().unwrap_or("").to_owned();
+        let target = irc_msg.response_target().unwrap_or("").to_owned();
+        let sent_at = server_time(&irc_msg);
+        let msgid = tag(&irc_msg, "msgid").map(str::to_owned);
+        let bodies = match irc_msg.command {
             Command::PRIVMSG(channel, content) => {
-                info!("privmsg");
-                client
-                    .post(Event {
-                        header: Some(Header {
-                            client_type: ClientType::Irc.into(),
-                        }),
-                        body: Some(event::Body::MessageCreated(MessageCreated {
-                            nickname,
-                            channel,
-                            content,
-                            origin: "".to_owned(),
-                        })),
-                    })
-                    .await?;
+                if let Some(reply) = search::respond(&mut history, &content).await {
+                    for line in reply {
+                        sender.send_notice(&target, &line)?;
+                    }
+                }
+                vec![event::Body::MessageCreated(MessageCreated {
+                    nickname,
+                    channel,
+                    content,
+                    origin: "".to_owned(),
+                })]
+            }
+            Command::JOIN(channels, _, _) => {
+                membership(&nickname, &channels, MembershipChange::Joined, None)
+            }
+            Command::PART(channels, reason) => {
+                membership(&nickname, &channels, MembershipChange::Left, reason)
+            }
+            Command::KICK(channels, user, reason) => {
+                membership(&user, &channels, MembershipChange::Left, reason)
             }
-            _ => {}
+            Command::QUIT(reason) => membership(&nickname, "", MembershipChange::Left, reason),
+            _ => vec![],
+        };
+        for (i, body) in bodies.into_iter().enumerate() {
+            // A JOIN or PART of several channels makes an event for each of them.
+            let origin_id = msgid.as_ref().map(|id| match i {
+                0 => format!("irc:{}", id),
+                i => format!("irc:{}:{}", id, i),
+            });
+            post(&poster, body, sent_at, origin_id).await;
         }
     }
     Ok(())
 }
 
+/// The IRCv3 `server-time` tag of `message`, if the server sent one.
+fn server_time(message: &Message) -> Option<SystemTime> {
+    let time = DateTime::parse_from_rfc3339(tag(message, "time")?).ok()?;
+    Some(time.into())
+}
+
+fn tag<'a>(message: &'a Message, key: &str) -> Option<&'a str> {
+    let Tag(_, value) = message.tags.as_ref()?.iter().find(|Tag(k, _)| k == key)?;
+    value.as_deref()
+}
+
+/// One event for each of the comma-separated `channels`, or for the whole network if empty.
+fn membership(
+    nickname: &str,
+    channels: &str,
+    change: MembershipChange,
+    reason: Option<String>,
+) -> Vec<event::Body> {
+    channels
+        .split(',')
+        .map(|channel| {
+            event::Body::MembershipChanged(MembershipChanged {
+                nickname: nickname.to_owned(),
+                channel: channel.to_owned(),
+                change: change.into(),
+                reason: reason.clone().unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+/// Rejected events are dropped; if the server is unreachable, they're kept for later instead.
+#[instrument(skip(poster, body))]
+async fn post(
+    poster: &Poster,
+    body: event::Body,
+    sent_at: Option<SystemTime>,
+    origin_id: Option<String>,
+) {
+    let mut event = Event::new(ClientType::Irc, body);
+    if let Some(sent_at) = sent_at {
+        event.sent_at = Some(sent_at.into());
+    }
+    event.origin_id = origin_id.unwrap_or_default();
+    let resp = match poster.post(event).await {
+        Ok(Some(resp)) => resp,
+        Ok(None) => return,
+        Err(e) => {
+            metrics::SEND_FAILURES.with_label_values(&["server"]).inc();
+            error!("failed to send event: {:?}", e);
+            return;
+        }
+    };
+    metrics::MESSAGES_RELAYED
+        .with_label_values(&["to_server"])
+        .inc();
+    for d in resp.failures() {
+        warn!(
+            "not relayed to {:?}: {:?} {}",
+            d.destination(),
+            d.status(),
+            d.reason
+        );
+    }
+}
+
+/// Only returns if sending to IRC fails; the server going away is waited out.
 #[instrument]
 async fn handle_rpc_stream(
-    mut resp: Response<Streaming<Event>>,
+    mut subscription: Subscription,
     sender: Sender,
+    mut client: BouncerServiceClient<Channel>,
+    poster: Arc<Poster>,
+    notices: StatusNotices,
 ) -> anyhow::Result<()> {
-    let stream = resp.get_mut();
-    while let Some(e) = stream.try_next().await? {
-        match e.body {
+    loop {
+        let e = subscription.next().await;
+        if let Some(event::Body::Heartbeat(heartbeat)) = e.body {
+            if let Err(status) = client.ack_heartbeat(heartbeat).await {
+                warn!("failed to answer a heartbeat: {}", status);
+            }
+            continue;
+        }
+        // Already relayed when it happened, or coming back around a loop.
+        if e.replayed || !poster.is_new(&e) {
+            continue;
+        }
+        let wants_ack = e.wants_ack();
+        let span = info_span!("relay_to_irc", event_id = %e.id);
+        tracing::set_parent(&span, &e.trace_context);
+        let result = span.in_scope(|| match e.body {
             Some(event::Body::MessageCreated(MessageCreated {
                 nickname,
                 channel,
                 content,
                 ..
             })) => {
-                let mut is_codeblock = false;
-                for line in content.split_terminator('\n') {
-                    let message = if is_codeblock {
-                        Cow::Borrowed(line)
-                    } else {
-                        Cow::Owned(format!("<{}> {}", nickname, line))
-                    };
-                    sender.send_privmsg(&channel, &message)?;
-                    if line.contains("```") {
-                        is_codeblock = !is_codeblock;
-                    }
+                let result = send_message(&sender, &nickname, &channel, &content);
+                match result {
+                    Ok(()) => metrics::MESSAGES_RELAYED.with_label_values(&["to_irc"]),
+                    Err(_) => metrics::SEND_FAILURES.with_label_values(&["irc"]),
                 }
+                .inc();
+                result
+            }
+            Some(event::Body::BouncerStatusChanged(status)) => notices.send(&sender, &status),
+            Some(event::Body::SystemNotice(notice)) => {
+                sender.send_notice(&notice.channel, &notice.content)
+            }
+            _ => Ok(()),
+        });
+        if wants_ack {
+            let ack = DeliveryAck::new(ClientType::Irc, e.id.clone(), result.as_ref().map(|_| ()));
+            if let Err(status) = client.ack(ack).instrument(span).await {
+                warn!("failed to report the delivery of {}: {}", e.id, status);
             }
-            _ => {}
+        }
+        result?;
+    }
+}
+
+/// Notices posted into the joined channels when another bouncer goes down or comes back.
+///
+/// The messages can be set with `notice_up` and `notice_down` in the `[options]` section of
+/// `config.toml`; `{bridge}` is replaced with the name of the bouncer.
+#[derive(Debug)]
+struct StatusNotices {
+    channels: Vec<String>,
+    up: Option<String>,
+    down: Option<String>,
+}
+
+impl StatusNotices {
+    fn from_config(config: &Config) -> Self {
+        StatusNotices {
+            channels: config.channels.clone(),
+            up: config.get_option("notice_up").map(str::to_owned),
+            down: config.get_option("notice_down").map(str::to_owned),
+        }
+    }
+
+    fn send(&self, sender: &Sender, status: &BouncerStatusChanged) -> irc::error::Result<()> {
+        let template = match status.status() {
+            BouncerStatus::Up => self.up.as_deref(),
+            BouncerStatus::Down => self.down.as_deref(),
+            BouncerStatus::Unknown => return Ok(()),
+        };
+        let notice = status.notice(template);
+        for channel in &self.channels {
+            sender.send_notice(channel, &notice)?;
+        }
+        Ok(())
+    }
+}
+
+fn send_message(
+    sender: &Sender,
+    nickname: &str,
+    channel: &str,
+    content: &str,
+) -> irc::error::Result<()> {
+    let mut is_codeblock = false;
+    for line in content.split_terminator('\n') {
+        let message = if is_codeblock {
+            Cow::Borrowed(line)
+        } else {
+            Cow::Owned(format!("<{}> {}", nickname, line))
+        };
+        sender.send_privmsg(channel, &message)?;
+        if line.contains("```") {
+            is_codeblock = !is_codeblock;
         }
     }
     Ok(())
@@ -0,0 +1,21 @@
+use rendezvous_common::metrics::{register_int_counter_vec, IntCounterVec, Lazy};
+
+/// By direction, either `to_server` or `to_irc`.
+pub static MESSAGES_RELAYED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "rendezvous_irc_messages_relayed_total",
+        "Messages relayed by the IRC bouncer.",
+        &["direction"]
+    )
+    .unwrap()
+});
+
+/// By destination, either `server` or `irc`.
+pub static SEND_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "rendezvous_irc_send_failures_total",
+        "Messages the IRC bouncer failed to relay.",
+        &["destination"]
+    )
+    .unwrap()
+});
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let dir = std::fs::canonicalize(std::env::var("CARGO_MANIFEST_DIR")?)?;
     let workspace_dir = dir
@@ -5,6 +7,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .ok_or("unexpected")?
         .parent()
         .ok_or("unexpected")?;
-    tonic_build::compile_protos(workspace_dir.join("proto").join("base.proto"))?;
+    let proto_dir = workspace_dir.join("proto");
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("rendezvous_descriptor.bin"))
+        .compile(
+            &[proto_dir.join("base.proto"), proto_dir.join("admin.proto")],
+            &[proto_dir],
+        )?;
     Ok(())
 }
@@ -0,0 +1,566 @@
+//! Static HTML pages of the history, which any web server can publish:
+//!
+//! ```text
+//! <dir>/index.html                   the channels
+//! <dir>/<channel>/index.html         a calendar of the days with events
+//! <dir>/<channel>/<YYYY-MM-DD>.html  the events of a day
+//! ```
+//!
+//! Each event has an anchor named after its cursor in the history, so links to it keep working
+//! when the pages are made again.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, Utc};
+
+use crate::{
+    chatlog::Zone,
+    proto::MembershipChange,
+    record::{Body, Record},
+};
+
+/// As many as there are `.nick-N` colors in the style sheet.
+const NICKNAME_COLORS: u32 = 12;
+
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 1em auto; max-width: 60em; padding: 0 1em; }
+nav { margin-bottom: 1em; }
+.log { list-style: none; padding: 0; font-family: monospace; }
+.log li { padding: 0.1em 0.3em; }
+.log li:target { background: #ffc; }
+.time { color: #888; text-decoration: none; }
+.network { border-radius: 0.3em; color: #fff; font-size: 0.8em; padding: 0 0.3em; }
+.network.irc { background: #4a7; }
+.network.discord { background: #5865f2; }
+.network.unknown { background: #888; }
+.content { white-space: pre-wrap; overflow-wrap: anywhere; }
+.membership, .notice { color: #888; }
+pre { background: #f4f4f4; margin: 0.3em 0; padding: 0.5em; white-space: pre-wrap; }
+code { background: #f4f4f4; }
+.calendar { display: inline-table; margin: 0 1em 1em 0; text-align: right; }
+.calendar td { padding: 0.1em 0.3em; color: #bbb; }
+.calendar td a { font-weight: bold; }
+.nick-0 { color: #c0392b; } .nick-1 { color: #d35400; } .nick-2 { color: #b7950b; }
+.nick-3 { color: #27ae60; } .nick-4 { color: #16a085; } .nick-5 { color: #2980b9; }
+.nick-6 { color: #8e44ad; } .nick-7 { color: #c2185b; } .nick-8 { color: #5d4037; }
+.nick-9 { color: #00838f; } .nick-10 { color: #558b2f; } .nick-11 { color: #3949ab; }
+";
+
+struct Entry {
+    anchor: String,
+    time: DateTime<FixedOffset>,
+    record: Record,
+}
+
+/// The day whose page is being made.
+struct Day {
+    channel: String,
+    date: NaiveDate,
+    prev: Option<NaiveDate>,
+    entries: Vec<Entry>,
+}
+
+/// Writes the pages as the events are added, keeping only the events of one day at a time.
+pub struct Archive {
+    dir: PathBuf,
+    zone: Zone,
+    /// How many events each channel has on each day.
+    channels: BTreeMap<String, BTreeMap<NaiveDate, usize>>,
+    day: Option<Day>,
+    /// Pages of days.
+    written: usize,
+}
+
+impl Archive {
+    /// Writes the pages into `dir`, replacing the ones there. The pages of the days and the
+    /// channels which aren't there anymore are removed, and any other files are left alone.
+    pub fn new(dir: impl Into<PathBuf>, zone: Zone) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        remove_pages(&dir)?;
+        write_file(&dir.join("style.css"), STYLE)?;
+        Ok(Archive {
+            dir,
+            zone,
+            channels: BTreeMap::new(),
+            day: None,
+            written: 0,
+        })
+    }
+
+    /// Adds an event from the history, where `cursor` points at it, channel by channel and in
+    /// the order of the history. Events without a channel are left out.
+    ///
+    /// All the events of a channel must be added one after another; the pages of a channel
+    /// which comes back after another one are made again, with only the events added since.
+    ///
+    /// The page of a day is written once the next day starts. An event sent on a day which is
+    /// over already, as it is if it was received a little late, goes on the page being made.
+    pub fn add(&mut self, cursor: &str, time: DateTime<Utc>, record: Record) -> anyhow::Result<()> {
+        let channel = match record.body.channel() {
+            Some(channel) => channel.to_owned(),
+            None => return Ok(()),
+        };
+        let time = self.zone.at(time);
+        let date = time.naive_local().date();
+        let day = match self.day.take() {
+            Some(day) if day.channel == channel && day.date >= date => day,
+            Some(day) if day.channel == channel => {
+                self.write_day(&day, Some(date))?;
+                Day {
+                    channel,
+                    date,
+                    prev: Some(day.date),
+                    entries: vec![],
+                }
+            }
+            last => {
+                if let Some(day) = last {
+                    self.write_day(&day, None)?;
+                    self.write_calendar(&day.channel)?;
+                }
+                Day {
+                    channel,
+                    date,
+                    prev: None,
+                    entries: vec![],
+                }
+            }
+        };
+        let day = self.day.insert(day);
+        day.entries.push(Entry {
+            anchor: format!("e{}", cursor),
+            time,
+            record,
+        });
+        *self
+            .channels
+            .entry(day.channel.clone())
+            .or_default()
+            .entry(day.date)
+            .or_default() += 1;
+        Ok(())
+    }
+
+    /// Writes the rest of the pages. Returns how many pages of days were written.
+    pub fn finish(mut self) -> anyhow::Result<usize> {
+        if let Some(day) = self.day.take() {
+            self.write_day(&day, None)?;
+            self.write_calendar(&day.channel)?;
+        }
+        write_file(&self.dir.join("index.html"), &self.index())?;
+        Ok(self.written)
+    }
+
+    fn write_day(&mut self, day: &Day, next: Option<NaiveDate>) -> anyhow::Result<()> {
+        let channel_dir = self.dir.join(dir_name(&day.channel));
+        fs::create_dir_all(&channel_dir)?;
+        let html = day_page(&day.channel, day.date, &day.entries, day.prev, next);
+        write_file(&channel_dir.join(format!("{}.html", day.date)), &html)?;
+        self.written += 1;
+        Ok(())
+    }
+
+    fn write_calendar(&self, channel: &str) -> anyhow::Result<()> {
+        let days = &self.channels[channel];
+        let path = self.dir.join(dir_name(channel)).join("index.html");
+        write_file(&path, &calendar(channel, days))
+    }
+
+    fn index(&self) -> String {
+        let mut body = "<h1>Channels</h1>\n<ul>\n".to_owned();
+        for (channel, days) in &self.channels {
+            let events: usize = days.values().sum();
+            let first = days.keys().next().unwrap();
+            let last = days.keys().next_back().unwrap();
+            writeln!(
+                body,
+                "<li><a href=\"{}/index.html\">{}</a> {} events, {} to {}</li>",
+                escape(&dir_name(channel)),
+                escape(channel),
+                events,
+                first,
+                last,
+            )
+            .unwrap();
+        }
+        body.push_str("</ul>\n");
+        page("Channels", "", &body)
+    }
+}
+
+fn calendar(channel: &str, days: &BTreeMap<NaiveDate, usize>) -> String {
+    let mut body = format!(
+        "<nav><a href=\"../index.html\">Channels</a></nav>\n<h1>{}</h1>\n",
+        escape(channel)
+    );
+    let mut months: Vec<_> = days.keys().map(|d| (d.year(), d.month())).collect();
+    months.dedup();
+    for (year, month) in months {
+        let first = NaiveDate::from_ymd_opt(year, month, 1).unwrap();
+        writeln!(
+            body,
+            "<table class=\"calendar\">\n<caption>{}</caption>\n\
+             <tr><th>Mon</th><th>Tue</th><th>Wed</th><th>Thu</th><th>Fri</th><th>Sat</th><th>Sun</th></tr>",
+            first.format("%B %Y"),
+        )
+        .unwrap();
+        let mut cells =
+            vec!["<td></td>".to_owned(); first.weekday().num_days_from_monday() as usize];
+        let mut date = Some(first);
+        while let Some(d) = date.filter(|d| d.month() == month) {
+            cells.push(match days.get(&d) {
+                Some(events) => format!(
+                    "<td><a href=\"{}.html\" title=\"{} events\">{}</a></td>",
+                    d,
+                    events,
+                    d.day()
+                ),
+                None => format!("<td>{}</td>", d.day()),
+            });
+            date = d.succ_opt();
+        }
+        for week in cells.chunks(7) {
+            writeln!(body, "<tr>{}</tr>", week.concat()).unwrap();
+        }
+        body.push_str("</table>\n");
+    }
+    page(channel, "../", &body)
+}
+
+fn day_page(
+    channel: &str,
+    date: NaiveDate,
+    entries: &[Entry],
+    prev: Option<NaiveDate>,
+    next: Option<NaiveDate>,
+) -> String {
+    let link = |date: Option<NaiveDate>, text: &str| match date {
+        Some(date) => format!("<a href=\"{}.html\">{}</a>", date, text),
+        None => text.to_owned(),
+    };
+    let mut body = format!(
+        "<nav><a href=\"../index.html\">Channels</a> &rsaquo; <a href=\"index.html\">{}</a> \
+         &rsaquo; {} &nbsp; {} {}</nav>\n<h1>{} <small>{}</small></h1>\n<ol class=\"log\">\n",
+        escape(channel),
+        date,
+        link(prev, "&larr; previous day"),
+        link(next, "next day &rarr;"),
+        escape(channel),
+        date,
+    );
+    for entry in entries {
+        if let Some(line) = render_entry(entry) {
+            body.push_str(&line);
+            body.push('\n');
+        }
+    }
+    body.push_str("</ol>\n");
+    page(&format!("{} {}", channel, date), "../", &body)
+}
+
+fn render_entry(entry: &Entry) -> Option<String> {
+    let (class, nickname, content) = match &entry.record.body {
+        Body::MessageCreated {
+            nickname, content, ..
+        } => ("message", nickname.as_str(), render_content(content)),
+        Body::MembershipChanged {
+            nickname,
+            change,
+            reason,
+            ..
+        } => {
+            let what = match change {
+                MembershipChange::Joined => "joined",
+                MembershipChange::Left => "left",
+                MembershipChange::Unknown => return None,
+            };
+            let content = match reason.as_str() {
+                "" => what.to_owned(),
+                reason => format!("{} ({})", what, escape(reason)),
+            };
+            ("membership", nickname.as_str(), content)
+        }
+        Body::SystemNotice { content, .. } => ("notice", "", render_content(content)),
+        _ => return None,
+    };
+    let source = entry.record.source;
+    Some(format!(
+        "<li id=\"{anchor}\" class=\"{class}\"><a class=\"time\" href=\"#{anchor}\">{time}</a> \
+         <span class=\"network {network}\">{badge}</span> \
+         <span class=\"nick nick-{color}\">{nickname}</span> \
+         <span class=\"content\">{content}</span></li>",
+        anchor = entry.anchor,
+        class = class,
+        time = entry.time.format("%H:%M:%S"),
+        network = source.as_str(),
+        badge = source.display_name(),
+        color = nickname_color(nickname),
+        nickname = escape(nickname),
+        content = content,
+    ))
+}
+
+/// Renders code blocks fenced by ```, `inline code` and links.
+fn render_content(content: &str) -> String {
+    let mut html = String::new();
+    let blocks: Vec<_> = content.split("```").collect();
+    for (i, block) in blocks.iter().enumerate() {
+        let is_code = i % 2 == 1 && i + 1 < blocks.len();
+        if is_code {
+            // Like Discord, the rest of the opening line names the language.
+            let code = match block.split_once('\n') {
+                Some((language, code)) if !language.contains(char::is_whitespace) => code,
+                _ => block,
+            };
+            write!(
+                html,
+                "<pre><code>{}</code></pre>",
+                escape(code.trim_matches('\n'))
+            )
+            .unwrap();
+            continue;
+        }
+        if i % 2 == 1 {
+            // An unclosed fence.
+            html.push_str("```");
+        }
+        let spans: Vec<_> = block.split('`').collect();
+        for (j, span) in spans.iter().enumerate() {
+            if j % 2 == 1 && j + 1 < spans.len() && !span.is_empty() {
+                write!(html, "<code>{}</code>", escape(span)).unwrap();
+            } else if j % 2 == 1 {
+                // Between backticks which aren't code after all.
+                html.push('`');
+                html.push_str(&linkify(span));
+                if j + 1 < spans.len() {
+                    html.push('`');
+                }
+            } else {
+                html.push_str(&linkify(span));
+            }
+        }
+    }
+    html
+}
+
+fn linkify(text: &str) -> String {
+    let mut html = String::new();
+    let mut rest = text;
+    while let Some(start) = ["https://", "http://"]
+        .iter()
+        .filter_map(|scheme| rest.find(scheme))
+        .min()
+    {
+        let (before, url) = rest.split_at(start);
+        let end = url
+            .find(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"'))
+            .unwrap_or(url.len());
+        let mut url = &url[..end];
+        // Punctuation after a link is most likely not a part of it, except for the closing
+        // parenthesis of links to Wikipedia and the like.
+        loop {
+            let trimmed = url.trim_end_matches(&['.', ',', ';', ':', '!', '?', '\''][..]);
+            url = match trimmed.strip_suffix(')') {
+                Some(t) if t.matches('(').count() < trimmed.matches(')').count() => t,
+                _ if trimmed.len() < url.len() => trimmed,
+                _ => break,
+            };
+        }
+        html.push_str(&escape(before));
+        if url.ends_with("://") {
+            html.push_str(&escape(url));
+        } else {
+            write!(
+                html,
+                "<a href=\"{0}\" rel=\"nofollow\">{0}</a>",
+                escape(url)
+            )
+            .unwrap();
+        }
+        rest = &rest[start + url.len()..];
+    }
+    html.push_str(&escape(rest));
+    html
+}
+
+fn nickname_color(nickname: &str) -> u32 {
+    let hash = nickname
+        .bytes()
+        .fold(0u32, |hash, b| hash.wrapping_mul(31).wrapping_add(b as u32));
+    hash % NICKNAME_COLORS
+}
+
+fn page(title: &str, root: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n<link rel=\"stylesheet\" href=\"{}style.css\">\n</head>\n\
+         <body>\n{}</body>\n</html>\n",
+        escape(title),
+        root,
+        body
+    )
+}
+
+/// A directory name for `channel` that no other channel has, which needs no escaping in URLs.
+fn dir_name(channel: &str) -> String {
+    let mut name = String::new();
+    for c in channel.chars() {
+        if c.is_alphanumeric() || c == '-' {
+            name.push(c);
+        } else {
+            for b in c.to_string().bytes() {
+                write!(name, "_{:02x}", b).unwrap();
+            }
+        }
+    }
+    name
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Removes the pages of the channels in `dir`, and the directories left empty.
+fn remove_pages(dir: &Path) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let channel_dir = entry?.path();
+        if !channel_dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&channel_dir)? {
+            let path = entry?.path();
+            let stem = path.file_stem().and_then(|stem| stem.to_str());
+            let is_page = path.extension() == Some("html".as_ref())
+                && matches!(stem, Some(stem)
+                    if stem == "index" || NaiveDate::parse_from_str(stem, "%Y-%m-%d").is_ok());
+            if is_page {
+                fs::remove_file(&path)?;
+            }
+        }
+        // Fails if something else is in there, which is kept then.
+        let _ = fs::remove_dir(&channel_dir);
+    }
+    Ok(())
+}
+
+/// Replaces the file at once, so that it's never served half written.
+fn write_file(path: &Path, contents: &str) -> anyhow::Result<()> {
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            linkify("see https://example.com/a?b=1&c=2."),
+            "see <a href=\"https://example.com/a?b=1&amp;c=2\" rel=\"nofollow\">\
+             https://example.com/a?b=1&amp;c=2</a>."
+        );
+        assert_eq!(
+            linkify("(https://en.wikipedia.org/wiki/Rust_(language))"),
+            "(<a href=\"https://en.wikipedia.org/wiki/Rust_(language)\" rel=\"nofollow\">\
+             https://en.wikipedia.org/wiki/Rust_(language)</a>)"
+        );
+        assert_eq!(linkify("http:// <b>"), "http:// &lt;b&gt;");
+    }
+
+    #[test]
+    fn code() {
+        assert_eq!(render_content("a `<b>` c"), "a <code>&lt;b&gt;</code> c");
+        assert_eq!(
+            render_content("```rust\nfn main() {}\n```\nhttp://x.y"),
+            "<pre><code>fn main() {}</code></pre>\n\
+             <a href=\"http://x.y\" rel=\"nofollow\">http://x.y</a>"
+        );
+        assert_eq!(render_content("``` and `` `"), "``` and `` `");
+    }
+
+    #[test]
+    fn pages() {
+        let dir = std::env::temp_dir().join(format!("rendezvous-archive-{}", std::process::id()));
+        let _cleanup = scopeguard::guard((), |_| {
+            let _ = fs::remove_dir_all(&dir);
+        });
+        let message = |channel: &str| Record {
+            id: "".to_owned(),
+            source: crate::proto::ClientType::Irc,
+            sent_at: None,
+            received_at: None,
+            origin_id: "".to_owned(),
+            hops: vec![],
+            body: Body::MessageCreated {
+                nickname: "foo".to_owned(),
+                channel: channel.to_owned(),
+                content: "hello".to_owned(),
+                origin: "".to_owned(),
+            },
+        };
+        let zone = Zone::Fixed(FixedOffset::east_opt(0).unwrap());
+        // Left from before, when #a went back further and #c was archived as well.
+        for path in [
+            "_23a/2021-12-31.html",
+            "_23c/2021-12-31.html",
+            "_23c/notes.txt",
+        ] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let mut archive = Archive::new(&dir, zone).unwrap();
+        let events = [
+            ("1", "2022-01-01T23:59:00Z", "#a"),
+            ("2", "2022-01-02T00:01:00Z", "#a"),
+            // Late, but kept in the order of the history.
+            ("3", "2022-01-01T23:59:30Z", "#a"),
+            ("4", "2022-01-01T12:00:00Z", "#b"),
+        ];
+        for (cursor, time, channel) in events {
+            archive
+                .add(cursor, time.parse().unwrap(), message(channel))
+                .unwrap();
+        }
+        assert_eq!(archive.finish().unwrap(), 3);
+
+        let read = |path: &str| fs::read_to_string(dir.join(path)).unwrap();
+        let first = read("_23a/2022-01-01.html");
+        assert!(first.contains("id=\"e1\"") && !first.contains("id=\"e3\""));
+        assert!(first.contains("<a href=\"2022-01-02.html\">next day"));
+        let second = read("_23a/2022-01-02.html");
+        assert!(second.contains("id=\"e2\"") && second.contains("id=\"e3\""));
+        assert!(second.contains("<a href=\"2022-01-01.html\">&larr; previous day"));
+        assert!(read("_23b/index.html").contains("2022-01-01.html"));
+        assert!(read("index.html").contains("3 events"));
+        assert!(!dir.join("_23a/2021-12-31.html").exists());
+        assert!(!dir.join("_23c/2021-12-31.html").exists());
+        assert!(dir.join("_23c/notes.txt").exists());
+    }
+
+    #[test]
+    fn dir_names() {
+        assert_eq!(dir_name("#langdev"), "_23langdev");
+        assert_eq!(dir_name("#랭데브/1"), "_23랭데브_2f1");
+        assert_ne!(dir_name("#a"), dir_name("_23a"));
+    }
+}
@@ -0,0 +1,788 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::bail;
+use chrono::{DateTime, Local, NaiveDate};
+use clap::{Parser, Subcommand};
+use futures::prelude::*;
+use tonic::{
+    metadata::AsciiMetadataValue, service::interceptor::InterceptedService, transport::Channel,
+    Request, Status,
+};
+
+use rendezvous_common::{
+    archive::Archive,
+    chatlog::{ChatLog, Style, Zone},
+    import,
+    prost_types::Timestamp,
+    proto::{
+        admin::{
+            admin_service_client::AdminServiceClient, ExportHistoryRequest, ListRoutesRequest,
+            ListSubscriptionsRequest, Route, RouteRequest,
+        },
+        bouncer_service_client::BouncerServiceClient,
+        event,
+        history_service_client::HistoryServiceClient,
+        ClientType, Event, EventKind, HistoryEvent, HistoryRequest, ListChannelsRequest,
+        MessageCreated, PostOptions, PostResult, Scrollback, SearchRequest, SubscribeRequest,
+        SubscriptionFilter,
+    },
+    record::{Body, Format, Record},
+};
+
+/// Command-line client for a Rendezvous server.
+#[derive(Parser)]
+#[clap(version)]
+struct Opts {
+    /// Address of the server.
+    #[clap(long, env = "RENDEZVOUS_SERVER", default_value = rendezvous_common::DEFAULT_SERVER_URL)]
+    server: String,
+    /// Token for the admin service, needed by `status` and `routes`.
+    #[clap(long, env = "RENDEZVOUS_ADMIN_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Post a message to the bridged channels.
+    Post {
+        #[clap(long, short)]
+        channel: String,
+        #[clap(long, short, default_value = "rdvctl")]
+        nick: String,
+        /// Read from the standard input if not given.
+        content: Option<String>,
+        /// Wait until the bouncers report delivery.
+        #[clap(long)]
+        wait: bool,
+    },
+    /// Print the events relayed by the server.
+    ///
+    /// Each filter option may be repeated.
+    Tail {
+        /// Glob pattern for the channel.
+        #[clap(long = "channel")]
+        channels: Vec<String>,
+        /// message, rename, status, notice or membership.
+        #[clap(long = "kind", parse(try_from_str = parse_kind))]
+        kinds: Vec<EventKind>,
+        /// irc, discord, peer or unknown.
+        #[clap(long = "source")]
+        sources: Vec<ClientType>,
+        /// Glob pattern for the origin.
+        #[clap(long = "origin")]
+        origins: Vec<String>,
+        /// human, json (one object per line) or cbor.
+        #[clap(long, short, default_value = "human")]
+        format: Output,
+        /// Print the last events of each channel first.
+        #[clap(long, value_name = "EVENTS")]
+        scrollback: Option<u32>,
+        /// Print the events of the last minutes first.
+        #[clap(long, value_name = "MINUTES")]
+        scrollback_minutes: Option<u32>,
+    },
+    /// Write the relayed messages into daily plain-text logs of each channel.
+    ///
+    /// The logs are at DIR/NETWORK/CHANNEL/YYYY-MM-DD.log.
+    Log {
+        dir: PathBuf,
+        /// irssi or weechat.
+        #[clap(long, default_value = "irssi")]
+        style: Style,
+        /// Where the days start: local, UTC or an offset such as +09:00.
+        #[clap(long, default_value = "local")]
+        time_zone: Zone,
+        /// Glob pattern for the channel; may be repeated.
+        #[clap(long = "channel")]
+        channels: Vec<String>,
+    },
+    /// Print the latest events of a channel.
+    History {
+        channel: String,
+        /// Events older than this cursor, printed before each event.
+        #[clap(long)]
+        before: Option<String>,
+        #[clap(long, short, default_value = "20")]
+        limit: u32,
+    },
+    /// Make static HTML pages of the history, a page for each channel and day.
+    Archive {
+        dir: PathBuf,
+        /// Defaults to every channel in the history; may be repeated.
+        #[clap(long = "channel")]
+        channels: Vec<String>,
+        /// Where the days start: local, UTC or an offset such as +09:00.
+        #[clap(long, default_value = "local")]
+        time_zone: Zone,
+    },
+    /// Search the messages in the history, newest first.
+    Search {
+        /// Words which must all appear in the message.
+        words: Vec<String>,
+        #[clap(long = "channel")]
+        channels: Vec<String>,
+        #[clap(long = "nick")]
+        nicknames: Vec<String>,
+        /// irc, discord, peer or unknown.
+        #[clap(long = "source")]
+        sources: Vec<ClientType>,
+        #[clap(long, short, default_value = "20")]
+        limit: u32,
+    },
+    /// List the connected bouncers and other subscribers.
+    Status,
+    /// Manage which bouncers relay events to which.
+    #[clap(subcommand)]
+    Routes(RoutesCommand),
+    /// Post events logged by `tail` again.
+    ///
+    /// Bouncer status changes are skipped.
+    Replay {
+        /// Written by `rdvctl tail --format json` or `--format cbor`. Defaults to the standard
+        /// input.
+        file: Option<PathBuf>,
+        /// json or cbor.
+        #[clap(long, short, default_value = "json")]
+        format: Format,
+        /// Id of the first event to replay. Defaults to the first one in the log.
+        #[clap(long)]
+        from: Option<String>,
+        /// Id of the last event to replay. Defaults to the last one in the log.
+        #[clap(long)]
+        to: Option<String>,
+        /// Only print the events which would be replayed.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Write the whole history into a file, in the order it was stored.
+    Export {
+        /// Defaults to the standard output.
+        file: Option<PathBuf>,
+        /// json or cbor.
+        #[clap(long, short, default_value = "json")]
+        format: Format,
+    },
+    /// Add the events in files to the history, unless they are already there.
+    ///
+    /// The events are not relayed to the bouncers.
+    Import {
+        /// Written by `rdvctl export` or `rdvctl tail`, or logs kept by irssi or exported by
+        /// DiscordChatExporter as JSON. Defaults to the standard input.
+        files: Vec<PathBuf>,
+        /// json, cbor, irssi or discord.
+        #[clap(long, short, default_value = "json")]
+        format: Input,
+        /// Channel of irssi logs.
+        #[clap(long, required_if_eq("format", "irssi"))]
+        channel: Option<String>,
+        /// Network of irssi logs: irc, discord or unknown.
+        #[clap(long, default_value = "irc")]
+        network: ClientType,
+        /// Time zone of irssi logs: local, UTC or an offset such as +09:00.
+        #[clap(long, default_value = "local")]
+        time_zone: Zone,
+    },
+}
+
+#[derive(Subcommand)]
+enum RoutesCommand {
+    List,
+    Add(RouteArgs),
+    Remove(RouteArgs),
+    Pause(RouteArgs),
+    Resume(RouteArgs),
+}
+
+#[derive(clap::Args)]
+struct RouteArgs {
+    source: ClientType,
+    destination: ClientType,
+}
+
+#[derive(Clone, Copy)]
+enum Output {
+    Human,
+    Record(Format),
+}
+
+impl std::str::FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Output::Human),
+            _ => Ok(Output::Record(s.parse()?)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Input {
+    Record(Format),
+    Irssi,
+    Discord,
+}
+
+impl std::str::FromStr for Input {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "irssi" => Ok(Input::Irssi),
+            "discord" => Ok(Input::Discord),
+            _ => Ok(Input::Record(s.parse()?)),
+        }
+    }
+}
+
+/// In the local time zone.
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+fn parse_kind(s: &str) -> anyhow::Result<EventKind> {
+    Ok(match s {
+        "message" => EventKind::MessageCreated,
+        "rename" => EventKind::UserRenamed,
+        "status" => EventKind::BouncerStatusChanged,
+        "notice" => EventKind::SystemNotice,
+        "membership" => EventKind::MembershipChanged,
+        _ => bail!("unknown event kind: {}", s),
+    })
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let opts = Opts::parse();
+    match opts.command {
+        Command::Post {
+            ref channel,
+            ref nick,
+            ref content,
+            wait,
+        } => {
+            let content = match content {
+                Some(content) => content.clone(),
+                None => {
+                    let mut content = String::new();
+                    io::stdin().read_to_string(&mut content)?;
+                    content.trim_end_matches('\n').to_owned()
+                }
+            };
+            let mut event = Event::new(
+                ClientType::Unknown,
+                event::Body::MessageCreated(MessageCreated {
+                    nickname: nick.clone(),
+                    channel: channel.clone(),
+                    content,
+                    origin: "".to_owned(),
+                }),
+            );
+            event.options = Some(PostOptions {
+                wait_for_delivery: wait,
+                ..Default::default()
+            });
+            let mut client = BouncerServiceClient::connect(opts.server).await?;
+            let result = client.post(event).await?.into_inner();
+            print_deliveries(&result);
+        }
+        Command::Tail {
+            channels,
+            kinds,
+            sources,
+            origins,
+            format,
+            scrollback,
+            scrollback_minutes,
+        } => {
+            let mut req = SubscribeRequest::new(ClientType::Unknown);
+            req.filter = Some(SubscriptionFilter {
+                channels,
+                kinds: kinds.into_iter().map(Into::into).collect(),
+                sources: sources.into_iter().map(Into::into).collect(),
+                origins,
+            });
+            if scrollback.is_some() || scrollback_minutes.is_some() {
+                req.scrollback = Some(Scrollback {
+                    events_per_channel: scrollback.unwrap_or_default(),
+                    minutes: scrollback_minutes.unwrap_or_default(),
+                });
+            }
+            tail(opts.server, req, format).await?;
+        }
+        Command::Log {
+            dir,
+            style,
+            time_zone,
+            channels,
+        } => {
+            let mut req = SubscribeRequest::new(ClientType::Unknown);
+            req.filter = Some(SubscriptionFilter {
+                channels,
+                kinds: vec![
+                    EventKind::MessageCreated.into(),
+                    EventKind::UserRenamed.into(),
+                    EventKind::MembershipChanged.into(),
+                ],
+                ..Default::default()
+            });
+            log(opts.server, req, ChatLog::new(dir, style, time_zone)).await?;
+        }
+        Command::History {
+            channel,
+            before,
+            limit,
+        } => {
+            let mut client = HistoryServiceClient::connect(opts.server).await?;
+            let response = client
+                .get_history(HistoryRequest {
+                    channel,
+                    before: before.unwrap_or_default(),
+                    limit,
+                    ..Default::default()
+                })
+                .await?
+                .into_inner();
+            if response.more {
+                println!("(more)");
+            }
+            print_history(&response.events);
+        }
+        Command::Archive {
+            dir,
+            mut channels,
+            time_zone,
+        } => {
+            let mut client = HistoryServiceClient::connect(opts.server).await?;
+            if channels.is_empty() {
+                channels = client
+                    .list_channels(ListChannelsRequest {})
+                    .await?
+                    .into_inner()
+                    .channels;
+            }
+            // The archive takes each channel once.
+            channels.sort();
+            channels.dedup();
+            let mut archive = Archive::new(&dir, time_zone)?;
+            for channel in channels {
+                // From the oldest event on.
+                let mut after = String::new();
+                loop {
+                    let response = client
+                        .get_history(HistoryRequest {
+                            channel: channel.clone(),
+                            after: after.clone(),
+                            limit: 500,
+                            earliest: true,
+                            ..Default::default()
+                        })
+                        .await?
+                        .into_inner();
+                    for event in &response.events {
+                        let record = match event.event.as_ref().and_then(Record::from_event) {
+                            Some(record) => record,
+                            None => continue,
+                        };
+                        let time = record.sent_at.or(record.received_at).or_else(|| {
+                            let time = SystemTime::try_from(event.time.clone()?).ok()?;
+                            Some(time.into())
+                        });
+                        let time = time.unwrap_or_else(chrono::Utc::now);
+                        archive.add(&event.cursor, time, record)?;
+                    }
+                    match response.events.last() {
+                        Some(last) if response.more => after = last.cursor.clone(),
+                        _ => break,
+                    }
+                }
+            }
+            let pages = archive.finish()?;
+            println!("wrote {} pages into {}", pages, dir.display());
+        }
+        Command::Search {
+            words,
+            channels,
+            nicknames,
+            sources,
+            limit,
+        } => {
+            let mut client = HistoryServiceClient::connect(opts.server).await?;
+            let response = client
+                .search(SearchRequest {
+                    query: words.join(" "),
+                    channels,
+                    nicknames,
+                    sources: sources.into_iter().map(Into::into).collect(),
+                    limit,
+                    ..Default::default()
+                })
+                .await?
+                .into_inner();
+            print_history(&response.events);
+            if response.more {
+                println!("(more)");
+            }
+        }
+        Command::Status => {
+            let mut client = admin_client(&opts).await?;
+            let subscriptions = client
+                .list_subscriptions(ListSubscriptionsRequest {})
+                .await?
+                .into_inner()
+                .subscriptions;
+            if subscriptions.is_empty() {
+                println!("nobody is connected");
+            }
+            let now = SystemTime::now();
+            for s in subscriptions {
+                let since = s
+                    .connected_since
+                    .clone()
+                    .and_then(|t| SystemTime::try_from(t).ok())
+                    .and_then(|t| now.duration_since(t).ok())
+                    .map(format_duration)
+                    .unwrap_or_default();
+                println!(
+                    "#{} {} from {}, connected for {}, {} queued, {} sent",
+                    s.id,
+                    s.client_type().display_name(),
+                    if s.peer_addr.is_empty() {
+                        "?"
+                    } else {
+                        &s.peer_addr
+                    },
+                    since,
+                    s.queue_depth,
+                    s.events_sent,
+                );
+            }
+        }
+        Command::Routes(ref command) => {
+            let mut client = admin_client(&opts).await?;
+            let route = match command {
+                RoutesCommand::List => {
+                    let routes = client
+                        .list_routes(ListRoutesRequest {})
+                        .await?
+                        .into_inner()
+                        .routes;
+                    for route in &routes {
+                        print_route(route);
+                    }
+                    return Ok(());
+                }
+                RoutesCommand::Add(args) => client.add_route(route_request(args)).await?,
+                RoutesCommand::Remove(args) => {
+                    let removed = client.remove_route(route_request(args)).await?;
+                    if !removed.get_ref().removed {
+                        bail!("no such route");
+                    }
+                    return Ok(());
+                }
+                RoutesCommand::Pause(args) => client.pause_route(route_request(args)).await?,
+                RoutesCommand::Resume(args) => client.resume_route(route_request(args)).await?,
+            };
+            print_route(route.get_ref());
+        }
+        Command::Replay {
+            ref file,
+            format,
+            ref from,
+            ref to,
+            dry_run,
+        } => {
+            let reader: Box<dyn Read> = match file {
+                Some(path) => Box::new(BufReader::new(File::open(path)?)),
+                None => Box::new(io::stdin()),
+            };
+            let mut client = BouncerServiceClient::connect(opts.server.clone()).await?;
+            let mut started = from.is_none();
+            for record in format.read(reader) {
+                let record = record?;
+                if !started && from.as_ref() == Some(&record.id) {
+                    started = true;
+                }
+                if started && !matches!(record.body, Body::BouncerStatusChanged { .. }) {
+                    println!("{}", render(&record));
+                    if !dry_run {
+                        let result = client.post(record.clone().into_replay()).await?;
+                        print_deliveries(result.get_ref());
+                    }
+                }
+                if to.as_ref() == Some(&record.id) {
+                    break;
+                }
+            }
+            if !started {
+                bail!("event {} is not in the log", from.as_deref().unwrap_or(""));
+            }
+        }
+        Command::Export { ref file, format } => {
+            let mut writer: Box<dyn Write> = match file {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(io::stdout()),
+            };
+            let mut client = admin_client(&opts).await?;
+            let mut events = client
+                .export_history(ExportHistoryRequest {})
+                .await?
+                .into_inner();
+            while let Some(event) = events.try_next().await? {
+                if let Some(record) = Record::from_event(&event) {
+                    format.write(&mut writer, &record)?;
+                }
+            }
+            writer.flush()?;
+        }
+        Command::Import {
+            ref files,
+            format,
+            ref channel,
+            network,
+            time_zone,
+        } => {
+            let readers: Vec<(Option<&PathBuf>, Box<dyn BufRead>)> = if files.is_empty() {
+                vec![(None, Box::new(BufReader::new(io::stdin())))]
+            } else {
+                files
+                    .iter()
+                    .map(|path| Ok((Some(path), Box::new(BufReader::new(File::open(path)?)) as _)))
+                    .collect::<io::Result<_>>()?
+            };
+            // Read every file first, so that nothing is imported from a broken one.
+            let mut events = vec![];
+            for (path, reader) in readers {
+                let records = match format {
+                    Input::Record(format) => format.read(reader).collect::<Result<Vec<_>, _>>(),
+                    Input::Irssi => {
+                        let channel = channel.as_deref().unwrap_or_default();
+                        let date = path.and_then(|p| date_in_file_name(p));
+                        import::irssi(reader, network, channel, time_zone, date)
+                    }
+                    Input::Discord => import::discord_chat_exporter(reader),
+                };
+                let records = records.map_err(|e| match path {
+                    Some(path) => e.context(format!("failed to read {}", path.display())),
+                    None => e,
+                })?;
+                events.extend(records.into_iter().map(Record::into_event));
+            }
+            let mut client = admin_client(&opts).await?;
+            let response = client
+                .import_history(stream::iter(events))
+                .await?
+                .into_inner();
+            println!(
+                "imported {} events, skipped {}",
+                response.imported, response.skipped
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn tail(server: String, req: SubscribeRequest, format: Output) -> anyhow::Result<()> {
+    let mut client = BouncerServiceClient::connect(server).await?;
+    let mut resp = client.clone().subscribe(req).await?;
+    let stream = resp.get_mut();
+
+    let stdout = io::stdout();
+    while let Some(event) = stream.try_next().await? {
+        if let Some(event::Body::Heartbeat(heartbeat)) = event.body {
+            client.ack_heartbeat(heartbeat).await?;
+            continue;
+        }
+        let record = match Record::from_event(&event) {
+            Some(record) => record,
+            None => continue,
+        };
+        let mut out = stdout.lock();
+        match format {
+            Output::Human => {
+                // As sent, or as received if the source didn't tell.
+                let time = record
+                    .sent_at
+                    .or(record.received_at)
+                    .map(|t| t.with_timezone(&Local).format(TIME_FORMAT).to_string())
+                    .unwrap_or_default();
+                let replayed = if event.replayed { " (replayed)" } else { "" };
+                writeln!(out, "{}{} {}", time, replayed, render(&record))?
+            }
+            Output::Record(format) => format.write(&mut out, &record)?,
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+async fn log(server: String, req: SubscribeRequest, mut log: ChatLog) -> anyhow::Result<()> {
+    let mut client = BouncerServiceClient::connect(server).await?;
+    let mut resp = client.clone().subscribe(req).await?;
+    let stream = resp.get_mut();
+
+    // Closes the logs of quiet channels soon after midnight.
+    let mut rotation = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        let event = tokio::select! {
+            event = stream.try_next() => match event? {
+                Some(event) => event,
+                None => break,
+            },
+            _ = rotation.tick() => {
+                log.rotate(chrono::Utc::now())?;
+                continue;
+            }
+        };
+        if let Some(event::Body::Heartbeat(heartbeat)) = event.body {
+            client.ack_heartbeat(heartbeat).await?;
+            continue;
+        }
+        if let Some(record) = Record::from_event(&event) {
+            log.write(&record)?;
+        }
+    }
+    Ok(())
+}
+
+// Interceptors fail with tonic's `Status`, large as it is.
+#[allow(clippy::result_large_err)]
+async fn admin_client(
+    opts: &Opts,
+) -> anyhow::Result<
+    AdminServiceClient<
+        InterceptedService<Channel, impl FnMut(Request<()>) -> Result<Request<()>, Status>>,
+    >,
+> {
+    let token = match &opts.token {
+        Some(token) => token,
+        None => bail!("the admin token is missing, set --token or RENDEZVOUS_ADMIN_TOKEN"),
+    };
+    let authorization: AsciiMetadataValue = format!("Bearer {}", token).parse()?;
+    let channel = Channel::from_shared(opts.server.clone())?.connect().await?;
+    Ok(AdminServiceClient::with_interceptor(
+        channel,
+        move |mut request: Request<()>| {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+            Ok(request)
+        },
+    ))
+}
+
+/// Like the logs written by `rdvctl log`.
+fn date_in_file_name(path: &Path) -> Option<NaiveDate> {
+    let name = path.file_stem()?.to_str()?;
+    (0..name.len().saturating_sub(9))
+        .filter_map(|i| name.get(i..i + 10))
+        .find_map(|s| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok())
+}
+
+fn route_request(args: &RouteArgs) -> RouteRequest {
+    RouteRequest {
+        source: args.source.into(),
+        destination: args.destination.into(),
+    }
+}
+
+fn render(record: &Record) -> String {
+    let source = record.source.as_str();
+    match &record.body {
+        Body::MessageCreated {
+            nickname,
+            channel,
+            content,
+            ..
+        } => format!("[{}] {} <{}> {}", source, channel, nickname, content),
+        Body::UserRenamed { old, new } => {
+            format!("[{}] {} is now known as {}", source, old, new)
+        }
+        Body::BouncerStatusChanged {
+            client_type,
+            status,
+            reason,
+        } => format!(
+            "[{}] {} is {} {}",
+            source,
+            client_type.display_name(),
+            status.as_str(),
+            reason
+        ),
+        Body::SystemNotice { channel, content } => {
+            format!("[{}] {} -- {}", source, channel, content)
+        }
+        Body::MembershipChanged {
+            nickname,
+            channel,
+            change,
+            reason,
+        } => format!(
+            "[{}] {} {} {} {}",
+            source,
+            nickname,
+            change.as_str(),
+            if channel.is_empty() {
+                "the network"
+            } else {
+                channel
+            },
+            reason
+        ),
+    }
+}
+
+/// Prints each event with its cursor and time.
+fn print_history(events: &[HistoryEvent]) {
+    for e in events {
+        let record = match e.event.as_ref().and_then(Record::from_event) {
+            Some(record) => record,
+            None => continue,
+        };
+        println!(
+            "{:>6} {} {}",
+            e.cursor,
+            format_time(&e.time),
+            render(&record)
+        );
+    }
+}
+
+fn format_time(time: &Option<Timestamp>) -> String {
+    time.clone()
+        .and_then(|t| SystemTime::try_from(t).ok())
+        .map(|t| DateTime::<Local>::from(t).format(TIME_FORMAT).to_string())
+        .unwrap_or_default()
+}
+
+fn print_route(route: &Route) {
+    println!(
+        "{} -> {}{}",
+        route.source().as_str(),
+        route.destination().as_str(),
+        if route.paused { " (paused)" } else { "" }
+    );
+}
+
+fn print_deliveries(result: &PostResult) {
+    for d in &result.deliveries {
+        println!(
+            "  {}: {:?} {}",
+            d.destination().as_str(),
+            d.status(),
+            d.reason
+        );
+    }
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        _ => format!("{}h {}m", secs / 3600, secs / 60 % 60),
+    }
+}
@@ -0,0 +1,488 @@
+//! Plain-text chat logs, one file per network, channel and day, laid out like the ones of irssi
+//! or WeeChat so that the usual tools can read them:
+//!
+//! ```text
+//! <dir>/<network>/<channel>/<YYYY-MM-DD>.log
+//! ```
+//!
+//! Renames and quits belong to no channel, so they are logged into every channel the person
+//! was last seen in.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc};
+
+use crate::{
+    proto::{ClientType, MembershipChange},
+    record::{Body, Record},
+};
+
+const ACTION_PREFIX: &str = "\u{1}ACTION ";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    Irssi,
+    Weechat,
+}
+
+impl FromStr for Style {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "irssi" => Ok(Style::Irssi),
+            "weechat" => Ok(Style::Weechat),
+            _ => anyhow::bail!("unknown log style: {}", s),
+        }
+    }
+}
+
+/// Which time zone the days start at midnight of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Zone {
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl Zone {
+    pub fn at(self, time: DateTime<Utc>) -> DateTime<FixedOffset> {
+        let offset = match self {
+            Zone::Local => Local.offset_from_utc_datetime(&time.naive_utc()).fix(),
+            Zone::Fixed(offset) => offset,
+        };
+        time.with_timezone(&offset)
+    }
+
+    /// The earlier one if `time` happens twice in the zone, as it may as clocks go back.
+    pub fn to_utc(self, time: NaiveDateTime) -> Option<DateTime<Utc>> {
+        let time = match self {
+            Zone::Local => Local
+                .from_local_datetime(&time)
+                .earliest()?
+                .with_timezone(&Utc),
+            Zone::Fixed(offset) => offset
+                .from_local_datetime(&time)
+                .earliest()?
+                .with_timezone(&Utc),
+        };
+        Some(time)
+    }
+}
+
+impl FromStr for Zone {
+    type Err = anyhow::Error;
+
+    /// `local`, `UTC`, or an offset from UTC such as `+09:00`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("local") {
+            return Ok(Zone::Local);
+        }
+        if s.eq_ignore_ascii_case("utc") || s == "Z" {
+            return Ok(Zone::Fixed(FixedOffset::east_opt(0).unwrap()));
+        }
+        let invalid = || anyhow::anyhow!("invalid time zone: {}", s);
+        let (sign, offset) = if let Some(offset) = s.strip_prefix('+') {
+            (1, offset)
+        } else if let Some(offset) = s.strip_prefix('-') {
+            (-1, offset)
+        } else {
+            return Err(invalid());
+        };
+        let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+        let hours: i32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+            .map(Zone::Fixed)
+            .ok_or_else(invalid)
+    }
+}
+
+/// A line for the log of a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Line {
+    channel: String,
+    time: DateTime<FixedOffset>,
+    text: String,
+}
+
+#[derive(Debug)]
+pub struct ChatLog {
+    dir: PathBuf,
+    style: Style,
+    zone: Zone,
+    /// By network, channel and day.
+    open: HashMap<(ClientType, String, NaiveDate), File>,
+    /// The channels each person was last seen in, by network and nickname.
+    seen_in: HashMap<(ClientType, String), BTreeSet<String>>,
+}
+
+impl ChatLog {
+    pub fn new(dir: impl Into<PathBuf>, style: Style, zone: Zone) -> Self {
+        ChatLog {
+            dir: dir.into(),
+            style,
+            zone,
+            open: HashMap::new(),
+            seen_in: HashMap::new(),
+        }
+    }
+
+    /// Appends `record` to the logs of its channels, at the time it was sent.
+    ///
+    /// Each line is appended with a single write, to the file of the day it was sent on. The
+    /// files stay open until [`ChatLog::rotate`] finds their days over, so that lines coming
+    /// out of order don't open and close them over and over.
+    pub fn write(&mut self, record: &Record) -> anyhow::Result<()> {
+        let time = record
+            .sent_at
+            .or(record.received_at)
+            .unwrap_or_else(Utc::now);
+        for line in self.lines(record, self.zone.at(time)) {
+            let key = (record.source, line.channel, line.time.naive_local().date());
+            let file = match self.open.get_mut(&key) {
+                Some(file) => file,
+                None => {
+                    let file = self.open_log(&key, line.time)?;
+                    self.open.entry(key).or_insert(file)
+                }
+            };
+            file.write_all(format!("{}\n", line.text).as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Closes the logs of the days before `now`, so that they are complete even if their
+    /// channels have been quiet since.
+    pub fn rotate(&mut self, now: DateTime<Utc>) -> anyhow::Result<()> {
+        let now = self.zone.at(now);
+        let today = now.naive_local().date();
+        let stale: Vec<_> = self
+            .open
+            .keys()
+            .filter(|&&(_, _, date)| date < today)
+            .cloned()
+            .collect();
+        for key in stale {
+            self.close(&key, now)?;
+        }
+        Ok(())
+    }
+
+    fn open_log(
+        &self,
+        (source, channel, date): &(ClientType, String, NaiveDate),
+        time: DateTime<FixedOffset>,
+    ) -> anyhow::Result<File> {
+        let dir = self.dir.join(source.as_str()).join(sanitize(channel));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.log", date.format("%Y-%m-%d")));
+        let mut file = open_append(&path)?;
+        if self.style == Style::Irssi {
+            let line = format!("--- Log opened {}\n", time.format("%a %b %d %H:%M:%S %Y"));
+            file.write_all(line.as_bytes())?;
+        }
+        Ok(file)
+    }
+
+    fn close(
+        &mut self,
+        key: &(ClientType, String, NaiveDate),
+        time: DateTime<FixedOffset>,
+    ) -> anyhow::Result<()> {
+        if let Some(mut file) = self.open.remove(key) {
+            if self.style == Style::Irssi {
+                // At the end of the day of the log, rather than whenever it was noticed.
+                let (_, _, date) = key;
+                let end = date.and_hms_opt(23, 59, 59).unwrap();
+                let end = time.offset().from_local_datetime(&end).unwrap();
+                let line = format!("--- Log closed {}\n", end.format("%a %b %d %H:%M:%S %Y"));
+                file.write_all(line.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn lines(&mut self, record: &Record, time: DateTime<FixedOffset>) -> Vec<Line> {
+        let style = self.style;
+        let line = |channel: &str, prefix: &str, text: String| Line {
+            channel: channel.to_owned(),
+            time,
+            text: match style {
+                Style::Irssi => format!("{} {}{}", time.format("%H:%M"), prefix, text),
+                Style::Weechat => format!("{}\t{}", time.format("%Y-%m-%d %H:%M:%S"), text),
+            },
+        };
+        let event =
+            |channel: &str, irssi: String, weechat_prefix: &str, weechat: String| match style {
+                Style::Irssi => line(channel, "-!- ", irssi),
+                Style::Weechat => line(channel, "", format!("{}\t{}", weechat_prefix, weechat)),
+            };
+        match &record.body {
+            Body::MessageCreated {
+                nickname,
+                channel,
+                content,
+                ..
+            } => {
+                self.seen(record.source, nickname, channel);
+                if let Some(action) = content.strip_prefix(ACTION_PREFIX) {
+                    let action = action.trim_end_matches('\u{1}');
+                    return vec![match style {
+                        Style::Irssi => line(channel, " * ", format!("{} {}", nickname, action)),
+                        Style::Weechat => line(channel, "", format!(" *\t{} {}", nickname, action)),
+                    }];
+                }
+                content
+                    .lines()
+                    .map(|text| match style {
+                        Style::Irssi => line(channel, "", format!("<{}> {}", nickname, text)),
+                        Style::Weechat => line(channel, "", format!("{}\t{}", nickname, text)),
+                    })
+                    .collect()
+            }
+            Body::MembershipChanged {
+                nickname,
+                channel,
+                change: MembershipChange::Joined,
+                ..
+            } if !channel.is_empty() => {
+                self.seen(record.source, nickname, channel);
+                let text = format!("{} has joined {}", nickname, channel);
+                vec![event(channel, text.clone(), "-->", text)]
+            }
+            Body::MembershipChanged {
+                nickname,
+                channel,
+                change: MembershipChange::Left,
+                reason,
+            } => {
+                let key = (record.source, nickname.clone());
+                let channels = if channel.is_empty() {
+                    self.seen_in.remove(&key).unwrap_or_default()
+                } else {
+                    if let Some(channels) = self.seen_in.get_mut(&key) {
+                        channels.remove(channel);
+                    }
+                    BTreeSet::from([channel.clone()])
+                };
+                let what = if channel.is_empty() {
+                    "has quit".to_owned()
+                } else {
+                    format!("has left {}", channel)
+                };
+                channels
+                    .iter()
+                    .map(|c| {
+                        event(
+                            c,
+                            format!("{} {} [{}]", nickname, what, reason),
+                            "<--",
+                            format!("{} {} ({})", nickname, what, reason),
+                        )
+                    })
+                    .collect()
+            }
+            Body::UserRenamed { old, new } => {
+                let channels = self
+                    .seen_in
+                    .remove(&(record.source, old.clone()))
+                    .unwrap_or_default();
+                let text = format!("{} is now known as {}", old, new);
+                let lines = channels
+                    .iter()
+                    .map(|c| event(c, text.clone(), "--", text.clone()))
+                    .collect();
+                self.seen_in.insert((record.source, new.clone()), channels);
+                lines
+            }
+            _ => vec![],
+        }
+    }
+
+    fn seen(&mut self, source: ClientType, nickname: &str, channel: &str) {
+        self.seen_in
+            .entry((source, nickname.to_owned()))
+            .or_default()
+            .insert(channel.to_owned());
+    }
+}
+
+/// Keeps channel names from escaping their directory.
+fn sanitize(channel: &str) -> String {
+    let name: String = channel
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '\0' => '_',
+            c => c,
+        })
+        .collect();
+    match name.as_str() {
+        "" | "." | ".." => format!("_{}", name),
+        _ => name,
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(source: ClientType, body: Body) -> Record {
+        Record {
+            id: "".to_owned(),
+            source,
+            sent_at: None,
+            received_at: None,
+            origin_id: "".to_owned(),
+            hops: vec![],
+            body,
+        }
+    }
+
+    fn message(nickname: &str, channel: &str, content: &str) -> Record {
+        record(
+            ClientType::Irc,
+            Body::MessageCreated {
+                nickname: nickname.to_owned(),
+                channel: channel.to_owned(),
+                content: content.to_owned(),
+                origin: "".to_owned(),
+            },
+        )
+    }
+
+    fn texts(log: &mut ChatLog, record: &Record) -> Vec<String> {
+        let time = "2022-01-02T03:04:05+09:00"
+            .parse::<DateTime<FixedOffset>>()
+            .unwrap();
+        log.lines(record, time)
+            .into_iter()
+            .map(|line| format!("{} {}", line.channel, line.text))
+            .collect()
+    }
+
+    #[test]
+    fn irssi() {
+        let mut log = ChatLog::new("", Style::Irssi, Zone::Local);
+        assert_eq!(
+            texts(&mut log, &message("foo", "#a", "hello\nworld")),
+            ["#a 03:04 <foo> hello", "#a 03:04 <foo> world"]
+        );
+        assert_eq!(
+            texts(&mut log, &message("foo", "#b", "\u{1}ACTION waves\u{1}")),
+            ["#b 03:04  * foo waves"]
+        );
+        let renamed = record(
+            ClientType::Irc,
+            Body::UserRenamed {
+                old: "foo".to_owned(),
+                new: "bar".to_owned(),
+            },
+        );
+        assert_eq!(
+            texts(&mut log, &renamed),
+            [
+                "#a 03:04 -!- foo is now known as bar",
+                "#b 03:04 -!- foo is now known as bar"
+            ]
+        );
+        let quit = record(
+            ClientType::Irc,
+            Body::MembershipChanged {
+                nickname: "bar".to_owned(),
+                channel: "".to_owned(),
+                change: MembershipChange::Left,
+                reason: "bye".to_owned(),
+            },
+        );
+        assert_eq!(
+            texts(&mut log, &quit),
+            [
+                "#a 03:04 -!- bar has quit [bye]",
+                "#b 03:04 -!- bar has quit [bye]"
+            ]
+        );
+        assert!(texts(&mut log, &quit).is_empty());
+    }
+
+    #[test]
+    fn weechat() {
+        let mut log = ChatLog::new("", Style::Weechat, Zone::Local);
+        assert_eq!(
+            texts(&mut log, &message("foo", "#a", "hello")),
+            ["#a 2022-01-02 03:04:05\tfoo\thello"]
+        );
+        let joined = record(
+            ClientType::Discord,
+            Body::MembershipChanged {
+                nickname: "foo".to_owned(),
+                channel: "#a".to_owned(),
+                change: MembershipChange::Joined,
+                reason: "".to_owned(),
+            },
+        );
+        assert_eq!(
+            texts(&mut log, &joined),
+            ["#a 2022-01-02 03:04:05\t-->\tfoo has joined #a"]
+        );
+    }
+
+    #[test]
+    fn zone() {
+        assert_eq!("local".parse::<Zone>().unwrap(), Zone::Local);
+        assert_eq!(
+            "+09:00".parse::<Zone>().unwrap(),
+            Zone::Fixed(FixedOffset::east_opt(9 * 3600).unwrap())
+        );
+        assert!("-0330".parse::<Zone>().is_err());
+        assert_eq!(
+            "-03:30".parse::<Zone>().unwrap(),
+            Zone::Fixed(FixedOffset::west_opt(3 * 3600 + 30 * 60).unwrap())
+        );
+        assert!("Asia/Seoul".parse::<Zone>().is_err());
+        assert!("".parse::<Zone>().is_err());
+        assert!("한국".parse::<Zone>().is_err());
+    }
+
+    #[test]
+    fn days() {
+        let dir = std::env::temp_dir().join(format!("rendezvous-chatlog-{}", std::process::id()));
+        let _cleanup = scopeguard::guard((), |_| {
+            let _ = fs::remove_dir_all(&dir);
+        });
+        let zone = Zone::Fixed(FixedOffset::east_opt(0).unwrap());
+        let mut log = ChatLog::new(&dir, Style::Weechat, zone);
+        let at = |time: &str, content: &str| {
+            let mut record = message("foo", "#a", content);
+            record.sent_at = Some(time.parse().unwrap());
+            record
+        };
+        // Out of order across midnight.
+        log.write(&at("2022-01-01T23:59:00Z", "1")).unwrap();
+        log.write(&at("2022-01-02T00:01:00Z", "2")).unwrap();
+        log.write(&at("2022-01-01T23:59:30Z", "3")).unwrap();
+        assert_eq!(log.open.len(), 2);
+        log.rotate("2022-01-02T00:05:00Z".parse().unwrap()).unwrap();
+        assert_eq!(log.open.len(), 1);
+
+        let read = |date: &str| {
+            let path = dir.join("irc").join("#a").join(format!("{}.log", date));
+            let text = fs::read_to_string(path).unwrap();
+            text.lines()
+                .map(|line| line.rsplit('\t').next().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(read("2022-01-01"), ["1", "3"]);
+        assert_eq!(read("2022-01-02"), ["2"]);
+    }
+}
@@ -0,0 +1,62 @@
+/// Matches `text` against a shell-style `pattern`, ignoring ASCII case.
+///
+/// `*` matches any sequence of characters and `?` matches exactly one.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position right after the last `*` and the text position it was tried at.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(&c) if c == '?' || c.eq_ignore_ascii_case(&text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    p = bp;
+                    t = bt + 1;
+                    backtrack = Some((bp, bt + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn literal() {
+        assert!(glob_match("#langdev", "#langdev"));
+        assert!(glob_match("#LangDev", "#langdev"));
+        assert!(!glob_match("#langdev", "#langdev-temp"));
+        assert!(!glob_match("#langdev-temp", "#langdev"));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("#langdev*", "#langdev"));
+        assert!(glob_match("#langdev*", "#langdev-temp"));
+        assert!(glob_match("#*-temp", "#langdev-temp"));
+        assert!(glob_match("#*a*b", "#xaxxab"));
+        assert!(!glob_match("#*a*b", "#xaxxa"));
+        assert!(glob_match("#lang?ev", "#langdev"));
+        assert!(!glob_match("#lang?ev", "#langev"));
+    }
+
+    #[test]
+    fn non_ascii() {
+        assert!(glob_match("#랭*", "#랭디브"));
+        assert!(!glob_match("#랭?", "#랭디브"));
+    }
+}
@@ -0,0 +1,362 @@
+//! Chat logs kept before the bridge, read into records which can be imported into the history.
+//!
+//! The records get ids made of where they were in the logs, so that importing a log again, or
+//! another log of the same channel overlapping it, doesn't add them twice.
+
+use std::collections::HashMap;
+use std::io::{BufRead, Read};
+
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use serde::Deserialize;
+
+use crate::{
+    chatlog::Zone,
+    proto::{ClientType, MembershipChange},
+    record::{Body, Record},
+};
+
+/// Reads an irssi log of `channel` on `network`, whose times are in `zone`.
+///
+/// Logs only tell the day when they are opened and when it changes; `date` is the day the log
+/// starts on if it doesn't tell, as in logs written by `rdvctl log`, whose file names tell
+/// instead. Lines other than messages, actions, joins, parts, kicks, quits and renames are
+/// skipped.
+pub fn irssi(
+    reader: impl BufRead,
+    network: ClientType,
+    channel: &str,
+    zone: Zone,
+    mut date: Option<NaiveDate>,
+) -> anyhow::Result<Vec<Record>> {
+    let mut records = vec![];
+    let mut ids = Ids::default();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if let Some(opened) = line.strip_prefix("--- Log opened ") {
+            let opened = NaiveDateTime::parse_from_str(opened, "%a %b %d %H:%M:%S %Y")?;
+            date = Some(opened.date());
+            continue;
+        }
+        if let Some(changed) = line.strip_prefix("--- Day changed ") {
+            date = Some(NaiveDate::parse_from_str(changed, "%a %b %d %Y")?);
+            continue;
+        }
+        let (time, rest) = match line.split_once(' ') {
+            Some((time, rest)) => match parse_time(time) {
+                Some(time) => (time, rest),
+                None => continue,
+            },
+            None => continue,
+        };
+        let date = date.ok_or_else(|| {
+            anyhow::anyhow!("line {}: the log doesn't tell which day it is", i + 1)
+        })?;
+        let body = match irssi_body(rest, channel) {
+            Some(body) => body,
+            None => continue,
+        };
+        let local = date.and_time(time);
+        let time = zone
+            .to_utc(local)
+            .ok_or_else(|| anyhow::anyhow!("line {}: {} doesn't exist", i + 1, local))?;
+        let scope = match body.channel() {
+            Some(channel) => channel.to_owned(),
+            None => format!("~{}", body.nickname().unwrap_or_default()),
+        };
+        let key = format!(
+            "{}:{}:{}",
+            network.as_str(),
+            scope,
+            local.format("%Y-%m-%dT%H:%M:%S")
+        );
+        records.push(Record {
+            id: format!("irssi:{}:{}", key, ids.next(&key)),
+            source: network,
+            sent_at: Some(time),
+            received_at: Some(time),
+            origin_id: "".to_owned(),
+            hops: vec![],
+            body,
+        });
+    }
+    Ok(records)
+}
+
+fn parse_time(time: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+        .ok()
+}
+
+fn irssi_body(line: &str, channel: &str) -> Option<Body> {
+    let message = |nickname: &str, content: String| Body::MessageCreated {
+        nickname: nickname.to_owned(),
+        channel: channel.to_owned(),
+        content,
+        origin: "".to_owned(),
+    };
+    let membership =
+        |nickname: &str, channel: &str, change, reason: &str| Body::MembershipChanged {
+            nickname: nickname.to_owned(),
+            channel: channel.to_owned(),
+            change,
+            reason: reason.to_owned(),
+        };
+    if let Some(rest) = line.strip_prefix('<') {
+        let (nickname, content) = rest.split_once("> ")?;
+        // With the mode of the nickname in the channel, like `<@op>` or `< user>`.
+        let nickname = nickname.trim_start_matches(&[' ', '@', '+', '%', '&', '~'][..]);
+        return Some(message(nickname, content.to_owned()));
+    }
+    if let Some(rest) = line.strip_prefix(" * ") {
+        let (nickname, action) = rest.split_once(' ').unwrap_or((rest, ""));
+        return Some(message(nickname, format!("\u{1}ACTION {}\u{1}", action)));
+    }
+    let rest = line.strip_prefix("-!- ")?;
+    let (nickname, rest) = rest.split_once(' ')?;
+    // Joins, parts and quits come with the user and host.
+    let rest = match rest.strip_prefix('[') {
+        Some(host) => host.split_once("] ")?.1,
+        None => rest,
+    };
+    // At the end, like `has quit [Client Quit]`.
+    let reason = |rest: &str| {
+        rest.split_once('[')
+            .and_then(|(_, reason)| reason.trim_end().strip_suffix(']'))
+            .unwrap_or("")
+            .to_owned()
+    };
+    if rest.starts_with("has joined ") {
+        Some(membership(nickname, channel, MembershipChange::Joined, ""))
+    } else if rest.starts_with("has left ") || rest.starts_with("was kicked from ") {
+        Some(membership(
+            nickname,
+            channel,
+            MembershipChange::Left,
+            &reason(rest),
+        ))
+    } else if rest.starts_with("has quit") {
+        Some(membership(
+            nickname,
+            "",
+            MembershipChange::Left,
+            &reason(rest),
+        ))
+    } else {
+        let new = rest.strip_prefix("is now known as ")?;
+        Some(Body::UserRenamed {
+            old: nickname.to_owned(),
+            new: new.trim().to_owned(),
+        })
+    }
+}
+
+/// Numbers the events which would otherwise get the same id.
+#[derive(Default)]
+struct Ids(HashMap<String, usize>);
+
+impl Ids {
+    fn next(&mut self, key: &str) -> usize {
+        let count = self.0.entry(key.to_owned()).or_default();
+        *count += 1;
+        *count - 1
+    }
+}
+
+#[derive(Deserialize)]
+struct DiscordExport {
+    channel: DiscordChannel,
+    messages: Vec<DiscordMessage>,
+}
+
+#[derive(Deserialize)]
+struct DiscordChannel {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct DiscordMessage {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    timestamp: DateTime<FixedOffset>,
+    content: String,
+    author: DiscordAuthor,
+    #[serde(default)]
+    attachments: Vec<DiscordAttachment>,
+}
+
+#[derive(Deserialize)]
+struct DiscordAuthor {
+    name: String,
+    /// The nickname in the server, which older versions of the exporter leave out.
+    #[serde(default)]
+    nickname: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DiscordAttachment {
+    url: String,
+}
+
+/// Reads a channel exported by DiscordChatExporter as JSON.
+///
+/// Messages are named after the channel and the nickname of the author in the server, as the
+/// Discord bouncer does; the links to their attachments are added to their content. Of other
+/// kinds of messages, only members joining the server are read.
+pub fn discord_chat_exporter(reader: impl Read) -> anyhow::Result<Vec<Record>> {
+    let export: DiscordExport = serde_json::from_reader(reader)?;
+    let channel = format!("#{}", export.channel.name);
+    let records = export.messages.into_iter().filter_map(|m| {
+        let nickname = m.author.nickname.unwrap_or(m.author.name);
+        let body = match m.kind.as_str() {
+            "Default" | "Reply" => {
+                let mut content = m.content;
+                for attachment in m.attachments {
+                    if !content.is_empty() {
+                        content.push('\n');
+                    }
+                    content.push_str(&attachment.url);
+                }
+                if content.is_empty() {
+                    return None;
+                }
+                Body::MessageCreated {
+                    nickname,
+                    channel: channel.clone(),
+                    content,
+                    origin: "".to_owned(),
+                }
+            }
+            "GuildMemberJoin" => Body::MembershipChanged {
+                nickname,
+                channel: "".to_owned(),
+                change: MembershipChange::Joined,
+                reason: "".to_owned(),
+            },
+            _ => return None,
+        };
+        let time = m.timestamp.with_timezone(&Utc);
+        Some(Record {
+            id: format!("discord:{}", m.id),
+            source: ClientType::Discord,
+            sent_at: Some(time),
+            received_at: Some(time),
+            origin_id: "".to_owned(),
+            hops: vec![],
+            body,
+        })
+    });
+    Ok(records.collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const IRSSI: &str = "\
+--- Log opened Mon Jan 02 23:58:00 2017
+23:58 <@foo> hello
+23:58 < bar> hi
+23:58  * bar waves
+23:59 -!- baz [~baz@example.com] has joined #langdev
+23:59 -!- mode/#langdev [+o baz] by foo
+--- Day changed Tue Jan 03 2017
+00:00 -!- baz [~baz@example.com] has left #langdev [bye]
+00:01 -!- bar was kicked from #langdev by foo [spam]
+00:02 -!- foo is now known as qux
+00:03 -!- qux [~foo@example.com] has quit [Quit: Leaving]
+";
+
+    #[test]
+    fn irssi_log() {
+        let zone = "+09:00".parse().unwrap();
+        let records = irssi(IRSSI.as_bytes(), ClientType::Irc, "#langdev", zone, None).unwrap();
+        let bodies: Vec<_> = records.iter().map(|r| &r.body).collect();
+        let message = |nickname: &str, content: &str| Body::MessageCreated {
+            nickname: nickname.to_owned(),
+            channel: "#langdev".to_owned(),
+            content: content.to_owned(),
+            origin: "".to_owned(),
+        };
+        let membership =
+            |nickname: &str, channel: &str, change, reason: &str| Body::MembershipChanged {
+                nickname: nickname.to_owned(),
+                channel: channel.to_owned(),
+                change,
+                reason: reason.to_owned(),
+            };
+        assert_eq!(
+            bodies,
+            [
+                &message("foo", "hello"),
+                &message("bar", "hi"),
+                &message("bar", "\u{1}ACTION waves\u{1}"),
+                &membership("baz", "#langdev", MembershipChange::Joined, ""),
+                &membership("baz", "#langdev", MembershipChange::Left, "bye"),
+                &membership("bar", "#langdev", MembershipChange::Left, "spam"),
+                &Body::UserRenamed {
+                    old: "foo".to_owned(),
+                    new: "qux".to_owned(),
+                },
+                &membership("qux", "", MembershipChange::Left, "Quit: Leaving"),
+            ]
+        );
+        assert_eq!(
+            records[4].sent_at.unwrap().to_rfc3339(),
+            "2017-01-02T15:00:00+00:00"
+        );
+        assert_eq!(records[0].id, "irssi:irc:#langdev:2017-01-02T23:58:00:0");
+        assert_eq!(records[1].id, "irssi:irc:#langdev:2017-01-02T23:58:00:1");
+        assert_eq!(records[7].id, "irssi:irc:~qux:2017-01-03T00:03:00:0");
+
+        let undated = irssi(
+            "12:00 <foo> hi".as_bytes(),
+            ClientType::Irc,
+            "#a",
+            zone,
+            None,
+        );
+        assert!(undated.is_err());
+    }
+
+    #[test]
+    fn discord_export() {
+        let export = r#"{
+            "guild": {"id": "1", "name": "langdev"},
+            "channel": {"id": "2", "type": "GuildTextChat", "name": "general"},
+            "messages": [
+                {
+                    "id": "10", "type": "Default", "timestamp": "2020-01-02T03:04:05.678+09:00",
+                    "content": "look", "author": {"id": "3", "name": "foo", "nickname": "Foo"},
+                    "attachments": [{"id": "4", "url": "https://cdn.example.com/a.png"}]
+                },
+                {
+                    "id": "11", "type": "GuildMemberJoin", "timestamp": "2020-01-02T03:05:00+09:00",
+                    "content": "", "author": {"id": "5", "name": "bar"}
+                },
+                {
+                    "id": "12", "type": "ChannelPinnedMessage", "timestamp": "2020-01-02T03:06:00+09:00",
+                    "content": "", "author": {"id": "3", "name": "foo"}
+                }
+            ]
+        }"#;
+        let records = discord_chat_exporter(export.as_bytes()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id, "discord:10");
+        assert_eq!(
+            records[0].body,
+            Body::MessageCreated {
+                nickname: "Foo".to_owned(),
+                channel: "#general".to_owned(),
+                content: "look\nhttps://cdn.example.com/a.png".to_owned(),
+                origin: "".to_owned(),
+            }
+        );
+        assert_eq!(
+            records[0].sent_at.unwrap().to_rfc3339(),
+            "2020-01-01T18:04:05.678+00:00"
+        );
+        assert_eq!(records[1].body.nickname(), Some("bar"));
+    }
+}
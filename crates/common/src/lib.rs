@@ -1,11 +1,41 @@
 #![warn(clippy::all)]
 
+pub mod archive;
+pub mod chatlog;
+pub mod glob;
+pub mod import;
+pub mod metrics;
+pub mod outbox;
 pub mod proto;
+pub mod recent;
+pub mod record;
+pub mod search;
+pub mod shutdown;
+pub mod subscription;
 pub mod tracing;
 
+use tonic::transport::{ClientTlsConfig, Endpoint};
+
+/// Where the bouncers and `rdvctl` reach the server unless told otherwise.
+pub const DEFAULT_SERVER_URL: &str = "http://[::1]:49252";
+
+/// The server at `url`, spoken to over TLS if it's `https://`, trusting the certificates the
+/// system does.
+pub fn endpoint(url: &str) -> anyhow::Result<Endpoint> {
+    let endpoint = Endpoint::from_shared(url.to_owned())?;
+    if endpoint.uri().scheme_str() == Some("https") {
+        return Ok(endpoint.tls_config(ClientTlsConfig::new())?);
+    }
+    Ok(endpoint)
+}
+
 pub use anyhow;
+pub use chrono;
 pub use futures;
+pub use prost;
+pub use prost_types;
 pub use serde;
 pub use serde_cbor;
+pub use serde_json;
 pub use tokio;
 pub use tonic;
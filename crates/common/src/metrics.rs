@@ -0,0 +1,39 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+pub use once_cell::sync::Lazy;
+pub use prometheus::*;
+
+/// Serves the metrics in the default registry at `http://{addr}/metrics`.
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    let make_service =
+        make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle_request)) });
+    Server::try_bind(&addr)?.serve(make_service).await?;
+    Ok(())
+}
+
+async fn handle_request(req: Request<Body>) -> std::result::Result<Response<Body>, Infallible> {
+    if req.uri().path() != "/metrics" {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(resp);
+    }
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    if let Err(e) = encoder.encode(&gather(), &mut buf) {
+        let mut resp = Response::new(Body::from(e.to_string()));
+        *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return Ok(resp);
+    }
+    let mut resp = Response::new(Body::from(buf));
+    resp.headers_mut().insert(
+        CONTENT_TYPE,
+        encoder.format_type().parse().expect("infallible"),
+    );
+    Ok(resp)
+}
@@ -0,0 +1,328 @@
+//! Events a bouncer couldn't post while the server was unreachable, kept on disk and posted
+//! again in order once it's back.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex as SyncMutex;
+use std::time::Duration;
+
+use anyhow::Context;
+use prost::Message;
+use tokio::sync::Mutex;
+use tonic::{transport::Channel, Code, Request, Status};
+
+use crate::proto::{bouncer_service_client::BouncerServiceClient, Event, PostResult};
+use crate::recent::RecentIds;
+use crate::tracing::{self, info, warn};
+
+pub const DEFAULT_CAPACITY: usize = 1000;
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// The size of the head offset at the start of the file.
+const HEADER_LEN: u64 = 8;
+/// The file is rewritten without the events taken out of it once they take up this much and
+/// more than the rest.
+const COMPACT_LEN: u64 = 64 * 1024;
+
+/// A queue of events in a file, which drops the oldest ones beyond its capacity.
+///
+/// The file starts with the offset of the first event still queued, which is moved past the
+/// events taken out of it, and the events are appended after it.
+#[derive(Debug)]
+pub struct Outbox {
+    path: PathBuf,
+    file: File,
+    capacity: usize,
+    /// With their sizes in the file.
+    events: VecDeque<(Event, u64)>,
+    /// Where the first event starts in the file.
+    head: u64,
+    /// Where the next event will be written.
+    len: u64,
+    /// How many events were taken out, to tell them apart.
+    popped: u64,
+}
+
+impl Outbox {
+    /// Reads the events kept in `path` by an earlier run, if any.
+    pub fn open(path: impl Into<PathBuf>, capacity: usize) -> anyhow::Result<Self> {
+        let path = path.into();
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e).context(format!("failed to read {}", path.display())),
+        };
+        let mut events = VecDeque::new();
+        if data.len() >= HEADER_LEN as usize {
+            let (header, _) = data.split_at(HEADER_LEN as usize);
+            let head = u64::from_le_bytes(header.try_into().expect("the header is 8 bytes long"));
+            // Past the end if the file was cut short after the last event was taken out.
+            let mut buf = &data[head.clamp(HEADER_LEN, data.len() as u64) as usize..];
+            while !buf.is_empty() {
+                let before = buf.len();
+                match Event::decode_length_delimited(&mut buf) {
+                    Ok(event) => events.push_back((event, (before - buf.len()) as u64)),
+                    Err(e) => {
+                        // Cut short while appending it.
+                        warn!(
+                            "dropping a broken event at the end of {}: {}",
+                            path.display(),
+                            e
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+        let (file, len) = rewrite(&path, &events)?;
+        Ok(Outbox {
+            path,
+            file,
+            capacity,
+            events,
+            head: HEADER_LEN,
+            len,
+            popped: 0,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// The first event, with the number of events taken out before it.
+    pub fn front(&self) -> Option<(u64, &Event)> {
+        self.events.front().map(|(event, _)| (self.popped, event))
+    }
+
+    pub fn push(&mut self, event: Event) -> anyhow::Result<()> {
+        let buf = event.encode_length_delimited_to_vec();
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file
+            .write_all(&buf)
+            .with_context(|| format!("failed to write {}", self.path.display()))?;
+        self.len += buf.len() as u64;
+        self.events.push_back((event, buf.len() as u64));
+        if self.events.len() > self.capacity {
+            warn!("the outbox is full, dropping the oldest event");
+            self.pop_front()?;
+        }
+        Ok(())
+    }
+
+    pub fn pop_front(&mut self) -> anyhow::Result<Option<Event>> {
+        let (event, size) = match self.events.pop_front() {
+            Some(front) => front,
+            None => return Ok(None),
+        };
+        self.popped += 1;
+        self.head += size;
+        if self.is_empty()
+            || (self.head >= COMPACT_LEN && self.head - HEADER_LEN > self.len - self.head)
+        {
+            self.compact()?;
+        } else {
+            self.write_head()?;
+        }
+        Ok(Some(event))
+    }
+
+    fn write_head(&mut self) -> anyhow::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file
+            .write_all(&self.head.to_le_bytes())
+            .with_context(|| format!("failed to write {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Rewrites the file with only the events still queued.
+    fn compact(&mut self) -> anyhow::Result<()> {
+        let (file, len) = rewrite(&self.path, &self.events)?;
+        self.file = file;
+        self.head = HEADER_LEN;
+        self.len = len;
+        Ok(())
+    }
+}
+
+/// Writes `events` to a new file at `path`, returning it opened with its length. The new file
+/// replaces the old one at once, so that a crash leaves either of them.
+fn rewrite(path: &Path, events: &VecDeque<(Event, u64)>) -> anyhow::Result<(File, u64)> {
+    let mut buf = HEADER_LEN.to_le_bytes().to_vec();
+    for (event, _) in events {
+        event.encode_length_delimited(&mut buf)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, &buf).with_context(|| format!("failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to write {}", path.display()))?;
+    let file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    Ok((file, buf.len() as u64))
+}
+
+/// Whether the server may take an event it failed to take this time.
+fn is_retryable(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::Unknown | Code::Cancelled | Code::DeadlineExceeded
+    )
+}
+
+/// Posts events to the server, keeping the ones it can't take for now in an [`Outbox`].
+///
+/// Once an event is kept, the ones after it are kept as well until [`Poster::retry`] posts
+/// them all, so that they arrive in order.
+#[derive(Debug)]
+pub struct Poster {
+    client: BouncerServiceClient<Channel>,
+    outbox: Mutex<Outbox>,
+    /// The origins of the events posted or received, to drop the ones coming back.
+    seen: SyncMutex<RecentIds>,
+}
+
+impl Poster {
+    pub fn new(client: BouncerServiceClient<Channel>, outbox: Outbox) -> Self {
+        if !outbox.is_empty() {
+            info!("{} events left in the outbox", outbox.len());
+        }
+        Poster {
+            client,
+            outbox: Mutex::new(outbox),
+            seen: Default::default(),
+        }
+    }
+
+    /// Whether `event`, received from the server, is new to the bouncer, which neither posted
+    /// nor received it before.
+    pub fn is_new(&self, event: &Event) -> bool {
+        self.seen.lock().unwrap().insert(event.origin_id())
+    }
+
+    /// Returns `None` if `event` was kept to be posted later.
+    ///
+    /// The outbox isn't held while posting, so that the events posted meanwhile don't wait for
+    /// the server.
+    pub async fn post(&self, event: Event) -> anyhow::Result<Option<PostResult>> {
+        if self.outbox.lock().await.is_empty() {
+            match self.send(&event).await {
+                Ok(result) => return Ok(Some(result)),
+                Err(status) if is_retryable(&status) => {
+                    warn!(
+                        "the server is unreachable, keeping events for later: {}",
+                        status
+                    );
+                }
+                Err(status) => return Err(status.into()),
+            }
+        }
+        self.outbox.lock().await.push(event)?;
+        Ok(None)
+    }
+
+    /// Every `interval`, posts the kept events until one of them fails again.
+    ///
+    /// Only returns if the outbox can't be written.
+    pub async fn retry(&self, interval: Duration) -> anyhow::Result<()> {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let mut posted = 0;
+            loop {
+                let (seq, event) = match self.outbox.lock().await.front() {
+                    Some((seq, event)) => (seq, event.clone()),
+                    None => break,
+                };
+                match self.send(&event).await {
+                    Ok(_) => posted += 1,
+                    Err(status) if is_retryable(&status) => break,
+                    Err(status) => warn!("the server rejected a kept event: {}", status),
+                }
+                let mut outbox = self.outbox.lock().await;
+                // Unless it was dropped for a newer one meanwhile.
+                if matches!(outbox.front(), Some((front, _)) if front == seq) {
+                    outbox.pop_front()?;
+                }
+            }
+            if posted > 0 {
+                let left = self.outbox.lock().await.len();
+                info!("posted {} kept events, {} left", posted, left);
+            }
+        }
+    }
+
+    async fn send(&self, event: &Event) -> Result<PostResult, Status> {
+        let mut request = Request::new(event.clone());
+        tracing::inject(&mut tracing::MetadataInjector(request.metadata_mut()));
+        let result = self.client.clone().post(request).await?.into_inner();
+        // The server gives the event its id as the origin if it has none.
+        let origin_id = match event.origin_id.as_str() {
+            "" => &result.event_id,
+            origin_id => origin_id,
+        };
+        self.seen.lock().unwrap().insert(origin_id);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proto::{event, ClientType, SystemNotice};
+
+    fn notice(content: &str) -> Event {
+        Event::new(
+            ClientType::Irc,
+            event::Body::SystemNotice(SystemNotice {
+                channel: "#a".to_owned(),
+                content: content.to_owned(),
+            }),
+        )
+    }
+
+    fn contents(outbox: &Outbox) -> Vec<&str> {
+        outbox
+            .events
+            .iter()
+            .map(|(e, _)| match &e.body {
+                Some(event::Body::SystemNotice(n)) => n.content.as_str(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn outbox() {
+        let path = std::env::temp_dir().join(format!("rendezvous-outbox-{}", std::process::id()));
+        let _cleanup = scopeguard::guard((), |_| {
+            let _ = fs::remove_file(&path);
+        });
+        let mut outbox = Outbox::open(&path, 2).unwrap();
+        assert!(outbox.is_empty());
+        for content in ["1", "2", "3"] {
+            outbox.push(notice(content)).unwrap();
+        }
+        assert_eq!(contents(&outbox), ["2", "3"]);
+
+        let mut outbox = Outbox::open(&path, 2).unwrap();
+        assert_eq!(contents(&outbox), ["2", "3"]);
+        outbox.pop_front().unwrap();
+        let mut outbox = Outbox::open(&path, 2).unwrap();
+        assert_eq!(contents(&outbox), ["3"]);
+
+        // An event cut short while appending it is dropped.
+        outbox.push(notice("4")).unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        outbox.file.set_len(len - 1).unwrap();
+        let mut outbox = Outbox::open(&path, 2).unwrap();
+        assert_eq!(contents(&outbox), ["3"]);
+        outbox.pop_front().unwrap();
+        assert!(Outbox::open(&path, 2).unwrap().is_empty());
+    }
+}
@@ -1,17 +1,255 @@
 tonic::include_proto!("org.langdev.rendezvous");
 
+pub mod admin {
+    tonic::include_proto!("org.langdev.rendezvous.admin");
+}
+
+/// Encoded descriptors of the protobuf files, for gRPC reflection.
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("rendezvous_descriptor");
+
 mod impls {
+    use std::fmt::Display;
+    use std::str::FromStr;
+    use std::time::SystemTime;
+
     use tonic::{Code, Status};
 
     use super::*;
+    use crate::glob::glob_match;
+
+    impl ClientType {
+        pub fn display_name(&self) -> &'static str {
+            match self {
+                ClientType::Unknown => "Unknown",
+                ClientType::Irc => "IRC",
+                ClientType::Discord => "Discord",
+                ClientType::Peer => "Peer",
+            }
+        }
+
+        /// Lowercase name, as used on the command line and in logs.
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                ClientType::Unknown => "unknown",
+                ClientType::Irc => "irc",
+                ClientType::Discord => "discord",
+                ClientType::Peer => "peer",
+            }
+        }
+
+        /// IRC and Discord, which have one bouncer each, unlike the other clients.
+        pub fn is_bouncer(&self) -> bool {
+            matches!(self, ClientType::Irc | ClientType::Discord)
+        }
+    }
+
+    impl FromStr for ClientType {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "unknown" => Ok(ClientType::Unknown),
+                "irc" => Ok(ClientType::Irc),
+                "discord" => Ok(ClientType::Discord),
+                "peer" => Ok(ClientType::Peer),
+                _ => anyhow::bail!("unknown client type: {}", s),
+            }
+        }
+    }
+
+    impl BouncerStatus {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                BouncerStatus::Unknown => "unknown",
+                BouncerStatus::Up => "up",
+                BouncerStatus::Down => "down",
+            }
+        }
+    }
+
+    impl MembershipChange {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                MembershipChange::Unknown => "unknown",
+                MembershipChange::Joined => "joined",
+                MembershipChange::Left => "left",
+            }
+        }
+    }
+
+    impl FromStr for MembershipChange {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "unknown" => Ok(MembershipChange::Unknown),
+                "joined" => Ok(MembershipChange::Joined),
+                "left" => Ok(MembershipChange::Left),
+                _ => anyhow::bail!("unknown membership change: {}", s),
+            }
+        }
+    }
+
+    impl FromStr for BouncerStatus {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "unknown" => Ok(BouncerStatus::Unknown),
+                "up" => Ok(BouncerStatus::Up),
+                "down" => Ok(BouncerStatus::Down),
+                _ => anyhow::bail!("unknown bouncer status: {}", s),
+            }
+        }
+    }
+
+    impl BouncerStatusChanged {
+        /// Renders a notice for the bridged channels from `template`, in which `{bridge}` is
+        /// replaced with the name of the bouncer.
+        pub fn notice(&self, template: Option<&str>) -> String {
+            let template = template.unwrap_or(match self.status() {
+                BouncerStatus::Down => "{bridge} bridge is down, messages will not be relayed.",
+                _ => "{bridge} bridge is back up, messages are relayed again.",
+            });
+            template.replace("{bridge}", self.client_type().display_name())
+        }
+    }
 
     impl PostResult {
-        pub fn new() -> Self {
-            Self {}
+        pub fn new(event_id: String) -> Self {
+            Self {
+                event_id,
+                deliveries: vec![],
+            }
+        }
+
+        /// Deliveries which didn't reach (or aren't on the way to) their destination.
+        pub fn failures(&self) -> impl Iterator<Item = &Delivery> {
+            self.deliveries.iter().filter(|d| !d.is_success())
+        }
+    }
+
+    impl Delivery {
+        pub fn new(destination: ClientType, subscription_id: u64, status: DeliveryStatus) -> Self {
+            Self {
+                destination: destination.into(),
+                subscription_id,
+                status: status.into(),
+                reason: String::new(),
+            }
+        }
+
+        pub fn is_success(&self) -> bool {
+            matches!(
+                self.status(),
+                DeliveryStatus::Queued | DeliveryStatus::Delivered | DeliveryStatus::Deferred
+            )
+        }
+    }
+
+    impl DeliveryAck {
+        pub fn new<E: Display>(
+            client_type: ClientType,
+            event_id: String,
+            result: Result<(), E>,
+        ) -> Self {
+            let (success, reason) = match result {
+                Ok(()) => (true, String::new()),
+                Err(e) => (false, e.to_string()),
+            };
+            Self {
+                header: Some(Header {
+                    client_type: client_type.into(),
+                }),
+                event_id,
+                success,
+                reason,
+            }
         }
     }
 
     impl Event {
+        /// Sent now.
+        pub fn new(client_type: ClientType, body: event::Body) -> Self {
+            Self {
+                header: Some(Header {
+                    client_type: client_type.into(),
+                }),
+                body: Some(body),
+                sent_at: Some(SystemTime::now().into()),
+                ..Default::default()
+            }
+        }
+
+        // The services return `Status` as it is, however large tonic made it.
+        #[allow(clippy::result_large_err)]
+        pub fn header(&self) -> Result<&Header, Status> {
+            match &self.header {
+                Some(header) => Ok(header),
+                None => Err(Status::new(Code::InvalidArgument, "missing header")),
+            }
+        }
+
+        pub fn kind(&self) -> EventKind {
+            match &self.body {
+                Some(event::Body::MessageCreated(_)) => EventKind::MessageCreated,
+                Some(event::Body::UserRenamed(_)) => EventKind::UserRenamed,
+                Some(event::Body::BouncerStatusChanged(_)) => EventKind::BouncerStatusChanged,
+                Some(event::Body::Heartbeat(_)) => EventKind::Heartbeat,
+                Some(event::Body::SystemNotice(_)) => EventKind::SystemNotice,
+                Some(event::Body::MembershipChanged(_)) => EventKind::MembershipChanged,
+                None => EventKind::Unknown,
+            }
+        }
+
+        pub fn channel(&self) -> Option<&str> {
+            match &self.body {
+                Some(event::Body::MessageCreated(m)) => Some(&m.channel),
+                Some(event::Body::SystemNotice(n)) => Some(&n.channel),
+                Some(event::Body::MembershipChanged(m)) if !m.channel.is_empty() => {
+                    Some(&m.channel)
+                }
+                _ => None,
+            }
+        }
+
+        pub fn origin(&self) -> &str {
+            match &self.body {
+                Some(event::Body::MessageCreated(m)) => &m.origin,
+                _ => "",
+            }
+        }
+
+        /// The id of the event where it first happened, which is its own id until the server
+        /// stamps it.
+        pub fn origin_id(&self) -> &str {
+            match self.origin_id.as_str() {
+                "" => &self.id,
+                origin_id => origin_id,
+            }
+        }
+
+        /// Whether the poster is waiting for this event to be acknowledged.
+        pub fn wants_ack(&self) -> bool {
+            self.options
+                .as_ref()
+                .map(|options| options.wait_for_delivery)
+                .unwrap_or_default()
+        }
+    }
+
+    impl SubscribeRequest {
+        pub fn new(client_type: ClientType) -> Self {
+            Self {
+                header: Some(Header {
+                    client_type: client_type.into(),
+                }),
+                ..Default::default()
+            }
+        }
+
+        // The services return `Status` as it is, however large tonic made it.
+        #[allow(clippy::result_large_err)]
         pub fn header(&self) -> Result<&Header, Status> {
             match &self.header {
                 Some(header) => Ok(header),
@@ -19,4 +257,27 @@ mod impls {
             }
         }
     }
+
+    impl SubscriptionFilter {
+        pub fn matches(&self, event: &Event) -> bool {
+            if event.kind() == EventKind::Heartbeat {
+                return true;
+            }
+            let source = event
+                .header
+                .as_ref()
+                .map(|h| h.client_type)
+                .unwrap_or_default();
+            (self.kinds.is_empty() || self.kinds.contains(&(event.kind() as i32)))
+                && (self.sources.is_empty() || self.sources.contains(&source))
+                && match event.channel() {
+                    Some(channel) if !self.channels.is_empty() => {
+                        self.channels.iter().any(|p| glob_match(p, channel))
+                    }
+                    _ => true,
+                }
+                && (self.origins.is_empty()
+                    || self.origins.iter().any(|p| glob_match(p, event.origin())))
+        }
+    }
 }
@@ -0,0 +1,69 @@
+//! The origins of the last events relayed, to tell the ones coming back around a loop.
+
+use std::collections::{HashSet, VecDeque};
+
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// A set of ids which forgets the oldest ones beyond its capacity.
+#[derive(Debug)]
+pub struct RecentIds {
+    capacity: usize,
+    ids: HashSet<String>,
+    /// Oldest first.
+    order: VecDeque<String>,
+}
+
+impl Default for RecentIds {
+    fn default() -> Self {
+        RecentIds::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl RecentIds {
+    pub fn new(capacity: usize) -> Self {
+        RecentIds {
+            capacity,
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns `false` if `id` was seen already. Empty ids are never seen.
+    pub fn insert(&mut self, id: &str) -> bool {
+        if id.is_empty() {
+            return true;
+        }
+        if !self.ids.insert(id.to_owned()) {
+            return false;
+        }
+        self.order.push_back(id.to_owned());
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capacity() {
+        let mut recent = RecentIds::new(2);
+        assert!(recent.insert("a"));
+        assert!(!recent.insert("a"));
+        assert!(recent.insert(""));
+        assert!(recent.insert(""));
+        assert!(recent.insert("b"));
+        assert!(recent.insert("c"));
+        assert!(!recent.contains("a"));
+        assert!(recent.contains("b") && recent.contains("c"));
+    }
+}
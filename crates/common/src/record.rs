@@ -0,0 +1,371 @@
+//! Events as plain serde records, for logging them to files and reading them back.
+//!
+//! Heartbeats are not recorded.
+
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::proto::{
+    event, BouncerStatus, BouncerStatusChanged, ClientType, Event, EventKind, MembershipChange,
+    MembershipChanged, MessageCreated, SystemNotice, UserRenamed,
+};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub id: String,
+    #[serde(with = "client_type")]
+    pub source: ClientType,
+    /// When the event happened at its source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<DateTime<Utc>>,
+    /// When the server received the event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<DateTime<Utc>>,
+    /// Where the event first happened, kept as it's relayed between servers.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub origin_id: String,
+    /// The servers which relayed the event, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hops: Vec<String>,
+    #[serde(flatten)]
+    pub body: Body,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Body {
+    MessageCreated {
+        nickname: String,
+        channel: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        origin: String,
+    },
+    UserRenamed {
+        old: String,
+        new: String,
+    },
+    BouncerStatusChanged {
+        #[serde(with = "client_type")]
+        client_type: ClientType,
+        #[serde(with = "bouncer_status")]
+        status: BouncerStatus,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        reason: String,
+    },
+    SystemNotice {
+        channel: String,
+        content: String,
+    },
+    MembershipChanged {
+        nickname: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        channel: String,
+        #[serde(with = "membership_change")]
+        change: MembershipChange,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        reason: String,
+    },
+}
+
+impl Body {
+    pub fn channel(&self) -> Option<&str> {
+        match self {
+            Body::MessageCreated { channel, .. } | Body::SystemNotice { channel, .. } => {
+                Some(channel)
+            }
+            Body::MembershipChanged { channel, .. } if !channel.is_empty() => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// The person who did it; the old nickname for renames.
+    pub fn nickname(&self) -> Option<&str> {
+        match self {
+            Body::MessageCreated { nickname, .. } | Body::MembershipChanged { nickname, .. } => {
+                Some(nickname)
+            }
+            Body::UserRenamed { old, .. } => Some(old),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Body::MessageCreated { .. } => EventKind::MessageCreated,
+            Body::UserRenamed { .. } => EventKind::UserRenamed,
+            Body::BouncerStatusChanged { .. } => EventKind::BouncerStatusChanged,
+            Body::SystemNotice { .. } => EventKind::SystemNotice,
+            Body::MembershipChanged { .. } => EventKind::MembershipChanged,
+        }
+    }
+}
+
+impl Record {
+    /// Returns `None` for heartbeats and events without a body.
+    pub fn from_event(event: &Event) -> Option<Self> {
+        let body = match event.body.clone()? {
+            event::Body::MessageCreated(MessageCreated {
+                nickname,
+                channel,
+                content,
+                origin,
+            }) => Body::MessageCreated {
+                nickname,
+                channel,
+                content,
+                origin,
+            },
+            event::Body::UserRenamed(UserRenamed { old, new }) => Body::UserRenamed { old, new },
+            event::Body::BouncerStatusChanged(status) => Body::BouncerStatusChanged {
+                client_type: status.client_type(),
+                status: status.status(),
+                reason: status.reason,
+            },
+            event::Body::SystemNotice(SystemNotice { channel, content }) => {
+                Body::SystemNotice { channel, content }
+            }
+            event::Body::MembershipChanged(m) => Body::MembershipChanged {
+                change: m.change(),
+                nickname: m.nickname,
+                channel: m.channel,
+                reason: m.reason,
+            },
+            event::Body::Heartbeat(_) => return None,
+        };
+        Some(Record {
+            id: event.id.clone(),
+            source: event
+                .header
+                .as_ref()
+                .map(|h| h.client_type())
+                .unwrap_or_default(),
+            sent_at: to_datetime(&event.sent_at),
+            received_at: to_datetime(&event.received_at),
+            origin_id: event.origin_id.clone(),
+            hops: event.hops.clone(),
+            body,
+        })
+    }
+
+    pub fn into_event(self) -> Event {
+        let body = match self.body {
+            Body::MessageCreated {
+                nickname,
+                channel,
+                content,
+                origin,
+            } => event::Body::MessageCreated(MessageCreated {
+                nickname,
+                channel,
+                content,
+                origin,
+            }),
+            Body::UserRenamed { old, new } => event::Body::UserRenamed(UserRenamed { old, new }),
+            Body::BouncerStatusChanged {
+                client_type,
+                status,
+                reason,
+            } => event::Body::BouncerStatusChanged(BouncerStatusChanged {
+                client_type: client_type.into(),
+                status: status.into(),
+                reason,
+            }),
+            Body::SystemNotice { channel, content } => {
+                event::Body::SystemNotice(SystemNotice { channel, content })
+            }
+            Body::MembershipChanged {
+                nickname,
+                channel,
+                change,
+                reason,
+            } => event::Body::MembershipChanged(MembershipChanged {
+                nickname,
+                channel,
+                change: change.into(),
+                reason,
+            }),
+        };
+        let mut event = Event::new(self.source, body);
+        event.id = self.id;
+        event.sent_at = self.sent_at.map(|t| SystemTime::from(t).into());
+        event.received_at = self.received_at.map(|t| SystemTime::from(t).into());
+        event.origin_id = self.origin_id;
+        event.hops = self.hops;
+        event
+    }
+
+    /// The event to post again as a new one, coming from no bouncer: so that it reaches the
+    /// bouncer which first relayed it as well, and isn't taken for one coming back around.
+    pub fn into_replay(self) -> Event {
+        Record {
+            id: String::new(),
+            source: ClientType::Unknown,
+            origin_id: String::new(),
+            hops: vec![],
+            ..self
+        }
+        .into_event()
+    }
+}
+
+fn to_datetime(timestamp: &Option<prost_types::Timestamp>) -> Option<DateTime<Utc>> {
+    let time = SystemTime::try_from(timestamp.clone()?).ok()?;
+    Some(time.into())
+}
+
+/// How records are laid out in a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON object per line.
+    Json,
+    /// A sequence of CBOR items.
+    Cbor,
+}
+
+impl Format {
+    pub fn write(self, mut writer: impl Write, record: &Record) -> anyhow::Result<()> {
+        match self {
+            Format::Json => {
+                serde_json::to_writer(&mut writer, record)?;
+                writer.write_all(b"\n")?;
+            }
+            Format::Cbor => serde_cbor::to_writer(&mut writer, record)?,
+        }
+        Ok(())
+    }
+
+    pub fn read<'a>(
+        self,
+        reader: impl Read + 'a,
+    ) -> Box<dyn Iterator<Item = anyhow::Result<Record>> + 'a> {
+        match self {
+            Format::Json => Box::new(
+                serde_json::Deserializer::from_reader(reader)
+                    .into_iter()
+                    .map(|r| r.map_err(Into::into)),
+            ),
+            Format::Cbor => Box::new(
+                serde_cbor::Deserializer::from_reader(reader)
+                    .into_iter()
+                    .map(|r| r.map_err(Into::into)),
+            ),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" | "jsonl" => Ok(Format::Json),
+            "cbor" => Ok(Format::Cbor),
+            _ => anyhow::bail!("unknown format: {}", s),
+        }
+    }
+}
+
+/// Serializes protobuf enums by their lowercase names.
+macro_rules! by_name {
+    ($module:ident, $ty:ty) => {
+        mod $module {
+            use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+            pub fn serialize<S: Serializer>(value: &$ty, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(value.as_str())
+            }
+
+            pub fn deserialize<'de, D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> Result<$ty, D::Error> {
+                String::deserialize(deserializer)?
+                    .parse()
+                    .map_err(D::Error::custom)
+            }
+        }
+    };
+}
+
+by_name!(client_type, crate::proto::ClientType);
+by_name!(bouncer_status, crate::proto::BouncerStatus);
+by_name!(membership_change, crate::proto::MembershipChange);
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn records() -> Vec<Record> {
+        vec![
+            Record {
+                id: "1".to_owned(),
+                source: ClientType::Irc,
+                sent_at: Some(Utc.timestamp_opt(1_600_000_000, 123_000_000).unwrap()),
+                received_at: Some(Utc.timestamp_opt(1_600_000_001, 0).unwrap()),
+                origin_id: "".to_owned(),
+                hops: vec![],
+                body: Body::MessageCreated {
+                    nickname: "foo".to_owned(),
+                    channel: "#langdev".to_owned(),
+                    content: "hello".to_owned(),
+                    origin: "".to_owned(),
+                },
+            },
+            Record {
+                id: "2".to_owned(),
+                source: ClientType::Discord,
+                sent_at: None,
+                received_at: None,
+                origin_id: "".to_owned(),
+                hops: vec![],
+                body: Body::BouncerStatusChanged {
+                    client_type: ClientType::Discord,
+                    status: BouncerStatus::Down,
+                    reason: "stream closed".to_owned(),
+                },
+            },
+            Record {
+                id: "3".to_owned(),
+                source: ClientType::Irc,
+                sent_at: Some(Utc.timestamp_opt(1_600_000_002, 0).unwrap()),
+                received_at: None,
+                origin_id: "".to_owned(),
+                hops: vec![],
+                body: Body::MembershipChanged {
+                    nickname: "bar".to_owned(),
+                    channel: "".to_owned(),
+                    change: MembershipChange::Left,
+                    reason: "Quit: bye".to_owned(),
+                },
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for format in [Format::Json, Format::Cbor] {
+            let mut buf = vec![];
+            for record in records() {
+                format.write(&mut buf, &record).unwrap();
+            }
+            let read: Vec<_> = format.read(&buf[..]).collect::<Result<_, _>>().unwrap();
+            assert_eq!(read, records());
+        }
+    }
+
+    #[test]
+    fn event() {
+        for record in records() {
+            assert_eq!(
+                Record::from_event(&record.clone().into_event()),
+                Some(record)
+            );
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! The `!search` bridge command, which searches the history from chat:
+//!
+//! ```text
+//! !search [from:NICKNAME] [on:irc|discord] [in:#CHANNEL] WORDS...
+//! ```
+//!
+//! Each filter may be repeated. Bouncers answer on their own platform, and relay the command
+//! itself like any other message.
+
+use std::time::SystemTime;
+
+use chrono::{DateTime, Local};
+use tonic::transport::Channel;
+
+use crate::{
+    proto::{
+        history_service_client::HistoryServiceClient, ClientType, HistoryEvent, SearchRequest,
+    },
+    record::{Body, Record},
+    tracing::error,
+};
+
+const COMMAND: &str = "!search";
+const USAGE: &str = "usage: !search [from:nickname] [on:irc|discord] [in:#channel] words...";
+const MAX_RESULTS: u32 = 3;
+/// In characters.
+const MAX_CONTENT_LENGTH: usize = 200;
+
+/// Returns `None` if `content` is not a search command, or the lines to answer with.
+pub async fn respond(
+    client: &mut HistoryServiceClient<Channel>,
+    content: &str,
+) -> Option<Vec<String>> {
+    let mut args = content.split_whitespace();
+    if args.next() != Some(COMMAND) {
+        return None;
+    }
+    let request = match parse(args) {
+        Ok(request) => request,
+        Err(e) => return Some(vec![format!("{}; {}", e, USAGE)]),
+    };
+    let response = match client.search(request).await {
+        Ok(response) => response.into_inner(),
+        Err(e) => {
+            error!("search failed: {:?}", e);
+            return Some(vec!["search failed, please try again later".to_owned()]);
+        }
+    };
+    if response.events.is_empty() {
+        return Some(vec!["no messages found".to_owned()]);
+    }
+    Some(response.events.iter().filter_map(format_result).collect())
+}
+
+fn parse<'a>(args: impl Iterator<Item = &'a str>) -> anyhow::Result<SearchRequest> {
+    let mut request = SearchRequest {
+        limit: MAX_RESULTS,
+        ..Default::default()
+    };
+    let mut words = vec![];
+    for arg in args {
+        if let Some(nickname) = arg.strip_prefix("from:") {
+            request.nicknames.push(nickname.to_owned());
+        } else if let Some(source) = arg.strip_prefix("on:") {
+            request.sources.push(source.parse::<ClientType>()?.into());
+        } else if let Some(channel) = arg.strip_prefix("in:") {
+            request.channels.push(channel.to_owned());
+        } else {
+            words.push(arg);
+        }
+    }
+    if words.is_empty() && request.nicknames.is_empty() {
+        anyhow::bail!("nothing to search for");
+    }
+    request.query = words.join(" ");
+    Ok(request)
+}
+
+fn format_result(result: &HistoryEvent) -> Option<String> {
+    let record = Record::from_event(result.event.as_ref()?)?;
+    let (nickname, channel, content) = match &record.body {
+        Body::MessageCreated {
+            nickname,
+            channel,
+            content,
+            ..
+        } => (nickname, channel, content),
+        _ => return None,
+    };
+    let time = result
+        .time
+        .clone()
+        .and_then(|t| SystemTime::try_from(t).ok())
+        .map(|t| {
+            DateTime::<Local>::from(t)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default();
+    let content = content.lines().collect::<Vec<_>>().join(" ");
+    let content = match content.char_indices().nth(MAX_CONTENT_LENGTH) {
+        Some((end, _)) => format!("{}…", &content[..end]),
+        None => content,
+    };
+    Some(format!(
+        "{} [{}] {} <{}> {}",
+        time,
+        record.source.as_str(),
+        channel,
+        nickname,
+        content
+    ))
+}
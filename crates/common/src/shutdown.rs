@@ -0,0 +1,19 @@
+use std::io;
+
+/// Waits until the process is asked to terminate, returning the name of the signal.
+#[cfg(unix)]
+pub async fn signal() -> io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.map(|()| "SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn signal() -> io::Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("SIGINT")
+}
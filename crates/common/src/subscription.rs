@@ -0,0 +1,63 @@
+//! A bouncer's subscription to the server, which outlasts the server going away.
+
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use tonic::{transport::Channel, Streaming};
+
+use crate::proto::{bouncer_service_client::BouncerServiceClient, Event, SubscribeRequest};
+use crate::tracing::{info, warn};
+
+pub const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Subscribes to the server again whenever the subscription fails or ends, so that the
+/// bouncer keeps running while the server is down.
+#[derive(Debug)]
+pub struct Subscription {
+    client: BouncerServiceClient<Channel>,
+    request: SubscribeRequest,
+    stream: Option<Streaming<Event>>,
+}
+
+impl Subscription {
+    /// Subscribes once the first event is asked for.
+    pub fn new(client: BouncerServiceClient<Channel>, request: SubscribeRequest) -> Self {
+        Subscription {
+            client,
+            request,
+            stream: None,
+        }
+    }
+
+    /// Waits for the next event, however long the server is down.
+    ///
+    /// Dropping the future before it's done loses no event, so it can be raced against others.
+    pub async fn next(&mut self) -> Event {
+        loop {
+            if self.stream.is_none() {
+                match self.client.subscribe(self.request.clone()).await {
+                    Ok(response) => {
+                        info!("subscribed to the server");
+                        self.stream = Some(response.into_inner());
+                    }
+                    Err(status) => {
+                        warn!(
+                            "failed to subscribe, trying again in {:?}: {}",
+                            RESUBSCRIBE_INTERVAL, status
+                        );
+                        tokio::time::sleep(RESUBSCRIBE_INTERVAL).await;
+                        continue;
+                    }
+                }
+            }
+            let stream = self.stream.as_mut().expect("subscribed just now");
+            match stream.try_next().await {
+                Ok(Some(event)) => return event,
+                Ok(None) => warn!("the server closed the subscription"),
+                Err(status) => warn!("the subscription failed: {}", status),
+            }
+            self.stream = None;
+            tokio::time::sleep(RESUBSCRIBE_INTERVAL).await;
+        }
+    }
+}
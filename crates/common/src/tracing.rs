@@ -1,9 +1,163 @@
 pub use tracing::*;
 
-pub fn init() -> Result<(), subscriber::SetGlobalDefaultError> {
-    tracing::subscriber::set_global_default(
-        tracing_subscriber::fmt::Subscriber::builder()
-            .compact()
-            .finish(),
-    )
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tonic::metadata::{KeyRef, MetadataKey, MetadataMap, MetadataValue};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, prelude::*, EnvFilter};
+
+/// Installs the global subscriber, configured with environment variables:
+///
+/// - `RUST_LOG`: filter directives, `info` by default.
+/// - `RENDEZVOUS_LOG_FORMAT`: `compact` (default) or `json`.
+/// - `RENDEZVOUS_LOG_DIR`: write to daily-rotated files named after `name` in this directory
+///   rather than to the standard output.
+/// - `OTEL_EXPORTER_OTLP_ENDPOINT`: also export spans to this OpenTelemetry collector over
+///   gRPC, as the `name` service unless `OTEL_SERVICE_NAME` says otherwise.
+///
+/// Logs may be lost once the returned guard is dropped, so keep it until the end of `main`.
+pub fn init(name: &str) -> anyhow::Result<Guard> {
+    let filter = EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new("info"))?;
+    let json = match std::env::var("RENDEZVOUS_LOG_FORMAT").as_deref() {
+        Ok("json") => true,
+        Ok("compact") | Err(_) => false,
+        Ok(format) => anyhow::bail!("unknown log format: {}", format),
+    };
+
+    let mut guard = Guard {
+        writer: None,
+        otlp: None,
+    };
+    let log_dir = std::env::var_os("RENDEZVOUS_LOG_DIR");
+    let ansi = log_dir.is_none();
+    let writer = match log_dir {
+        Some(dir) => {
+            let (writer, writer_guard) =
+                tracing_appender::non_blocking(tracing_appender::rolling::daily(dir, name));
+            guard.writer = Some(writer_guard);
+            BoxMakeWriter::new(writer)
+        }
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+    let (json_layer, compact_layer) = if json {
+        (
+            Some(tracing_subscriber::fmt::layer().json().with_writer(writer)),
+            None,
+        )
+    } else {
+        (
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .compact()
+                    .with_ansi(ansi)
+                    .with_writer(writer),
+            ),
+        )
+    };
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let otel_layer = match std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Some(_) => {
+            let service_name =
+                std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| name.to_owned());
+            // The exporter gets a runtime of its own, so that spans are exported regardless of
+            // the flavor of runtime the caller runs, or whether it is still running on exit.
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .thread_name("otlp-exporter")
+                .enable_all()
+                .build()?;
+            let tracer = {
+                let _enter = runtime.enter();
+                opentelemetry_otlp::new_pipeline()
+                    .tracing()
+                    .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_env())
+                    .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                        KeyValue::new("service.name", service_name),
+                    ])))
+                    .install_batch(opentelemetry::runtime::Tokio)?
+            };
+            guard.otlp = Some(runtime);
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json_layer)
+        .with(compact_layer)
+        .with(otel_layer)
+        .try_init()?;
+    Ok(guard)
+}
+
+/// Writes the W3C trace context of the current span into `carrier`, such as the metadata of a
+/// gRPC request (see [`MetadataInjector`]) or `Event::trace_context`.
+///
+/// Nothing is written unless spans are exported.
+pub fn inject(carrier: &mut dyn Injector) {
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, carrier));
+}
+
+/// Makes `span` a child of the span whose trace context was written into `carrier` by
+/// [`inject`], possibly in another process.
+pub fn set_parent(span: &Span, carrier: &dyn Extractor) {
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(carrier));
+    span.set_parent(parent);
+}
+
+pub struct MetadataInjector<'a>(pub &'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::from_str(&value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+pub struct MetadataExtractor<'a>(pub &'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .map(|key| match key {
+                KeyRef::Ascii(key) => key.as_str(),
+                KeyRef::Binary(key) => key.as_str(),
+            })
+            .collect()
+    }
+}
+
+/// Flushes the logs and exported spans when dropped.
+#[must_use]
+pub struct Guard {
+    writer: Option<WorkerGuard>,
+    otlp: Option<tokio::runtime::Runtime>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.otlp.take() {
+            opentelemetry::global::shutdown_tracer_provider();
+            runtime.shutdown_background();
+        }
+    }
 }
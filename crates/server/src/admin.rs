@@ -0,0 +1,266 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use uuid::Uuid;
+
+use rendezvous_common::{
+    anyhow,
+    futures::{prelude::*, stream::BoxStream},
+    proto::{
+        admin::{
+            admin_service_server::AdminService, DisconnectRequest, DisconnectResponse,
+            ExportHistoryRequest, ImportHistoryResponse, InjectNoticeRequest, ListRoutesRequest,
+            ListRoutesResponse, ListSubscriptionsRequest, ListSubscriptionsResponse,
+            RemoveRouteResponse, Route, RouteRequest,
+        },
+        event, ClientType, Event, PostResult, SystemNotice,
+    },
+    tonic::{self, Request, Response, Status, Streaming},
+    tracing::{self, debug, error, info, instrument},
+};
+
+use crate::{
+    hub::Hub,
+    storage::{self, Storage, StoredEvent},
+    DEFAULT_DELIVERY_TIMEOUT,
+};
+
+/// Events read from or written to the storage at once.
+const HISTORY_BATCH_SIZE: usize = 500;
+
+/// Rejects requests which don't carry `authorization: Bearer <token>` metadata.
+// Interceptors fail with tonic's `Status`, large as it is.
+#[allow(clippy::result_large_err)]
+pub fn authorize(token: String) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone {
+    let expected = format!("Bearer {}", token);
+    move |request: Request<()>| match request.metadata().get("authorization") {
+        Some(value) if constant_time_eq(value.as_bytes(), expected.as_bytes()) => Ok(request),
+        Some(_) => Err(Status::unauthenticated("invalid admin token")),
+        None => Err(Status::unauthenticated("missing admin token")),
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Debug)]
+pub struct AdminServiceImpl {
+    hub: Arc<Hub>,
+    storage: Arc<dyn Storage>,
+}
+
+impl AdminServiceImpl {
+    pub fn new(hub: Arc<Hub>, storage: Arc<dyn Storage>) -> Self {
+        AdminServiceImpl { hub, storage }
+    }
+
+    async fn set_route_paused(
+        &self,
+        request: Request<RouteRequest>,
+        paused: bool,
+    ) -> Result<Response<Route>, Status> {
+        let request = request.into_inner();
+        let (source, destination) = (request.source(), request.destination());
+        match self.hub.set_route_paused(source, destination, paused).await {
+            Some(route) => Ok(Response::new(route)),
+            None => Err(Status::not_found(format!(
+                "no route from {:?} to {:?}",
+                source, destination
+            ))),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl AdminService for AdminServiceImpl {
+    type ExportHistoryStream = BoxStream<'static, Result<Event, Status>>;
+    async fn list_subscriptions(
+        &self,
+        _request: Request<ListSubscriptionsRequest>,
+    ) -> Result<Response<ListSubscriptionsResponse>, Status> {
+        Ok(Response::new(ListSubscriptionsResponse {
+            subscriptions: self.hub.subscriptions().await,
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn disconnect(
+        &self,
+        request: Request<DisconnectRequest>,
+    ) -> Result<Response<DisconnectResponse>, Status> {
+        let request = request.into_inner();
+        let reason = if request.reason.is_empty() {
+            "disconnected by an administrator"
+        } else {
+            &request.reason
+        };
+        let disconnected = self.hub.unsubscribe(request.subscription_id, reason).await;
+        Ok(Response::new(DisconnectResponse { disconnected }))
+    }
+
+    async fn list_routes(
+        &self,
+        _request: Request<ListRoutesRequest>,
+    ) -> Result<Response<ListRoutesResponse>, Status> {
+        Ok(Response::new(ListRoutesResponse {
+            routes: self.hub.routes().await,
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn add_route(&self, request: Request<RouteRequest>) -> Result<Response<Route>, Status> {
+        let request = request.into_inner();
+        let (source, destination) = (request.source(), request.destination());
+        if source == ClientType::Unknown || destination == ClientType::Unknown {
+            return Err(Status::invalid_argument(
+                "both ends of a route must be bouncers",
+            ));
+        }
+        if source == destination {
+            return Err(Status::invalid_argument(
+                "a bouncer can't route events to itself",
+            ));
+        }
+        Ok(Response::new(self.hub.add_route(source, destination).await))
+    }
+
+    #[instrument(skip(self))]
+    async fn remove_route(
+        &self,
+        request: Request<RouteRequest>,
+    ) -> Result<Response<RemoveRouteResponse>, Status> {
+        let request = request.into_inner();
+        let removed = self
+            .hub
+            .remove_route(request.source(), request.destination())
+            .await;
+        Ok(Response::new(RemoveRouteResponse { removed }))
+    }
+
+    #[instrument(skip(self))]
+    async fn pause_route(&self, request: Request<RouteRequest>) -> Result<Response<Route>, Status> {
+        self.set_route_paused(request, true).await
+    }
+
+    #[instrument(skip(self))]
+    async fn resume_route(
+        &self,
+        request: Request<RouteRequest>,
+    ) -> Result<Response<Route>, Status> {
+        self.set_route_paused(request, false).await
+    }
+
+    #[instrument(skip(self))]
+    async fn inject_notice(
+        &self,
+        request: Request<InjectNoticeRequest>,
+    ) -> Result<Response<PostResult>, Status> {
+        debug!("{:?}", request);
+        if self.hub.is_draining() {
+            return Err(Status::unavailable("server is shutting down"));
+        }
+        let InjectNoticeRequest { channel, content } = request.into_inner();
+        info!("injecting a notice into {}", channel);
+        let mut event = Event::new(
+            ClientType::Unknown,
+            event::Body::SystemNotice(SystemNotice { channel, content }),
+        );
+        event.id = Uuid::new_v4().to_string();
+        event.received_at = Some(SystemTime::now().into());
+        self.hub.stamp(&mut event).await;
+        tracing::inject(&mut event.trace_context);
+        let mut result = PostResult::new(event.id.clone());
+        result.deliveries = self
+            .hub
+            .publish(ClientType::Unknown, &event, DEFAULT_DELIVERY_TIMEOUT)
+            .await;
+        Ok(Response::new(result))
+    }
+
+    #[instrument(skip(self))]
+    // The pages fail with the `Status` the stream ends with.
+    #[allow(clippy::result_large_err)]
+    async fn export_history(
+        &self,
+        _request: Request<ExportHistoryRequest>,
+    ) -> Result<Response<Self::ExportHistoryStream>, Status> {
+        let storage = Arc::clone(&self.storage);
+        // Where to export the next page from, until there are no more.
+        let pages = stream::try_unfold(Some(None), move |after| {
+            let storage = Arc::clone(&storage);
+            async move {
+                let after = match after {
+                    Some(after) => after,
+                    None => return Ok::<_, Status>(None),
+                };
+                let page = blocking(&storage, move |s| s.export(after, HISTORY_BATCH_SIZE)).await?;
+                let next = match page.events.last() {
+                    Some((position, _)) if page.more => Some(Some(*position)),
+                    _ => None,
+                };
+                Ok(Some((page.events, next)))
+            }
+        });
+        let events = pages
+            .map_ok(|events| stream::iter(events.into_iter().map(|(_, e)| Ok(e.into_event()))))
+            .try_flatten();
+        Ok(Response::new(events.boxed()))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn import_history(
+        &self,
+        request: Request<Streaming<Event>>,
+    ) -> Result<Response<ImportHistoryResponse>, Status> {
+        let mut events = request.into_inner();
+        let mut response = ImportHistoryResponse::default();
+        let mut batch = vec![];
+        loop {
+            let event = events.message().await?;
+            match event.as_ref().and_then(to_stored_event) {
+                Some(stored) => batch.push(stored),
+                None if event.is_some() => response.skipped += 1,
+                None => {}
+            }
+            if batch.len() >= HISTORY_BATCH_SIZE || (event.is_none() && !batch.is_empty()) {
+                let batch = std::mem::take(&mut batch);
+                let len = batch.len();
+                let imported = blocking(&self.storage, move |s| s.import(&batch)).await?;
+                response.imported += imported as u64;
+                response.skipped += (len - imported) as u64;
+            }
+            if event.is_none() {
+                break;
+            }
+        }
+        info!(
+            "imported {} events into the history, skipped {}",
+            response.imported, response.skipped
+        );
+        Ok(Response::new(response))
+    }
+}
+
+/// Returns `None` for events which can't be kept in the history.
+fn to_stored_event(event: &Event) -> Option<StoredEvent> {
+    if event.id.is_empty() {
+        return None;
+    }
+    let time = event
+        .received_at
+        .clone()
+        .or_else(|| event.sent_at.clone())?;
+    StoredEvent::new(SystemTime::try_from(time).ok()?, event)
+}
+
+async fn blocking<F, T>(storage: &Arc<dyn Storage>, f: F) -> Result<T, Status>
+where
+    F: FnOnce(&dyn Storage) -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    storage::blocking(storage, f).await.map_err(|e| {
+        error!("failed to access the history: {:?}", e);
+        Status::internal("failed to access the history")
+    })
+}
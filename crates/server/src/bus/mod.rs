@@ -0,0 +1,39 @@
+//! How servers running side by side share the events posted to each of them, so that bouncers
+//! can subscribe to any of them.
+//!
+//! Each server fans the events posted to it out to its own subscribers, then hands them to the
+//! bus in the background, which carries them to the other servers for their subscribers and
+//! their history. Each server keeps its own storage, so:
+//!
+//! - the history of a server lacks the events shared while it was down or cut off the bus;
+//! - only the server an event was posted to waits for the delivery reports, and keeps it for
+//!   offline bouncers, which get it once they subscribe to that server again;
+//! - each server only replaces the subscriptions made to itself, so there should be a single
+//!   bouncer of each type across the servers.
+//!
+//! Events the bus can't carry, because it's down or too far behind, are only logged.
+
+mod local;
+mod redis;
+
+use std::fmt::Debug;
+
+use rendezvous_common::{
+    anyhow,
+    futures::{future::BoxFuture, stream::BoxStream},
+    proto::Event,
+};
+
+pub use local::LocalBus;
+pub use redis::RedisBus;
+
+pub type BusStream = BoxStream<'static, anyhow::Result<Event>>;
+
+pub trait Bus: Debug + Send + Sync {
+    /// Hands `event`, posted to this server, to the other ones.
+    fn publish<'a>(&'a self, event: &'a Event) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// The events posted to the other servers from now on. Ends with an error if the bus
+    /// breaks.
+    fn subscribe(&self) -> BusStream;
+}
@@ -0,0 +1,202 @@
+//! A Redis pub/sub channel.
+//!
+//! Only plain TCP is spoken; reach a Redis which needs TLS through a tunnel like stunnel.
+
+use std::fmt::{self, Debug};
+use std::time::Duration;
+
+use redis::{aio::ConnectionManager, Client};
+use uuid::Uuid;
+
+use rendezvous_common::{
+    anyhow::{self, Context},
+    futures::{
+        future::{self, BoxFuture, FutureExt},
+        stream::{self, StreamExt, TryStreamExt},
+    },
+    prost::Message,
+    proto::Event,
+    tokio::{self, sync::OnceCell},
+};
+
+use super::{Bus, BusStream};
+
+/// For connecting, including the authentication, and subscribing.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// For a command to be answered.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Shares the events over a Redis channel, as the id of the server which published them, a
+/// newline, and the event in protobuf.
+pub struct RedisBus {
+    client: Client,
+    channel: String,
+    /// Tells the events published here from the others'.
+    id: String,
+    /// Connected when publishing first. Reconnects in the background once the connection
+    /// drops, failing the events published until then.
+    publisher: OnceCell<ConnectionManager>,
+}
+
+impl RedisBus {
+    /// Takes `url` as `redis://[[username]:password@]host[:port]`.
+    pub fn new(url: &str, channel: String) -> anyhow::Result<Self> {
+        if url.starts_with("rediss://") {
+            anyhow::bail!(
+                "TLS isn't supported, reach redis through a tunnel instead: {}",
+                url
+            );
+        }
+        let client = Client::open(url).with_context(|| format!("invalid redis URL: {}", url))?;
+        Ok(RedisBus {
+            client,
+            channel,
+            id: Uuid::new_v4().to_string(),
+            publisher: OnceCell::new(),
+        })
+    }
+
+    fn encode(&self, event: &Event) -> Vec<u8> {
+        let mut payload = format!("{}\n", self.id).into_bytes();
+        payload.reserve(event.encoded_len());
+        event.encode(&mut payload).expect("a Vec grows as it needs");
+        payload
+    }
+}
+
+impl Debug for RedisBus {
+    /// Without the password in the URL.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RedisBus")
+            .field("addr", &self.client.get_connection_info().addr)
+            .field("channel", &self.channel)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl Bus for RedisBus {
+    fn publish<'a>(&'a self, event: &'a Event) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let publisher = self
+                .publisher
+                .get_or_try_init(|| async {
+                    let connect = ConnectionManager::new(self.client.clone());
+                    tokio::time::timeout(CONNECT_TIMEOUT, connect)
+                        .await
+                        .context("timed out connecting to redis")?
+                        .context("failed to connect to redis")
+                })
+                .await?;
+            let mut conn = publisher.clone();
+            let mut command = redis::cmd("PUBLISH");
+            command.arg(&self.channel).arg(self.encode(event));
+            tokio::time::timeout(IO_TIMEOUT, command.query_async::<_, ()>(&mut conn))
+                .await
+                .context("redis didn't answer in time")??;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn subscribe(&self) -> BusStream {
+        let client = self.client.clone();
+        let channel = self.channel.clone();
+        let id = self.id.clone();
+        stream::once(async move {
+            let subscribe = async {
+                let mut pubsub = client.get_async_connection().await?.into_pubsub();
+                pubsub.subscribe(&channel).await?;
+                Ok::<_, anyhow::Error>(pubsub)
+            };
+            tokio::time::timeout(CONNECT_TIMEOUT, subscribe)
+                .await
+                .context("timed out subscribing to redis")?
+        })
+        .map_ok(move |pubsub| {
+            let id = id.clone();
+            // Ends once the connection drops.
+            pubsub
+                .into_on_message()
+                .filter_map(move |message| future::ready(decode(&id, message.get_payload_bytes())))
+        })
+        .try_flatten()
+        .boxed()
+    }
+}
+
+/// Returns `None` for the events published by the server with `id`.
+fn decode(id: &str, payload: &[u8]) -> Option<anyhow::Result<Event>> {
+    let (publisher, event) = match payload.iter().position(|&b| b == b'\n') {
+        Some(pos) => (&payload[..pos], &payload[pos + 1..]),
+        None => return Some(Err(anyhow::anyhow!("broken message on the bus"))),
+    };
+    if publisher == id.as_bytes() {
+        return None;
+    }
+    Some(Event::decode(event).context("broken event on the bus"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use rendezvous_common::{
+        proto::{event, ClientType, SystemNotice},
+        tokio,
+    };
+
+    use super::*;
+
+    #[test]
+    fn url() {
+        assert!(RedisBus::new("redis://localhost", "a".to_owned()).is_ok());
+        assert!(RedisBus::new("redis://me:secret@[::1]:6380/0", "a".to_owned()).is_ok());
+        assert!(RedisBus::new("http://localhost", "a".to_owned()).is_err());
+        assert!(RedisBus::new("rediss://localhost", "a".to_owned()).is_err());
+        let bus = RedisBus::new("redis://:secret@localhost", "a".to_owned()).unwrap();
+        assert!(!format!("{:?}", bus).contains("secret"));
+    }
+
+    #[test]
+    fn payload() {
+        let bus = RedisBus::new("redis://localhost", "a".to_owned()).unwrap();
+        let event = Event::new(
+            ClientType::Irc,
+            event::Body::SystemNotice(SystemNotice {
+                channel: "#a".to_owned(),
+                content: "hello".to_owned(),
+            }),
+        );
+        let payload = bus.encode(&event);
+        assert!(decode(&bus.id, &payload).is_none());
+        assert_eq!(decode("other", &payload).unwrap().unwrap(), event);
+        assert!(decode("other", b"broken").unwrap().is_err());
+    }
+
+    /// Needs a Redis server, at `RENDEZVOUS_TEST_REDIS_URL` or on localhost.
+    #[tokio::test]
+    #[ignore]
+    async fn redis() {
+        let url = std::env::var("RENDEZVOUS_TEST_REDIS_URL")
+            .unwrap_or_else(|_| "redis://localhost".to_owned());
+        let channel = format!("rendezvous-test-{}", Uuid::new_v4());
+        let a = RedisBus::new(&url, channel.clone()).unwrap();
+        let b = RedisBus::new(&url, channel).unwrap();
+        let mut events = b.subscribe();
+        let received = tokio::spawn(async move { events.try_next().await });
+        // Let the subscription reach Redis first.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let event = Event::new(
+            ClientType::Irc,
+            event::Body::SystemNotice(SystemNotice {
+                channel: "#a".to_owned(),
+                content: "hello".to_owned(),
+            }),
+        );
+        b.publish(&event).await.unwrap();
+        a.publish(&event).await.unwrap();
+        let received = received.await.unwrap().unwrap();
+        assert_eq!(received, Some(event));
+    }
+}
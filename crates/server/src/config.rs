@@ -0,0 +1,147 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rendezvous_common::anyhow::{self, Context};
+
+use crate::{hub, peer::Peer, server, storage::Retention};
+
+const DEFAULT_LISTEN: &str = "[::1]:49252";
+
+/// Settings read from `RENDEZVOUS_*` environment variables.
+#[derive(Debug)]
+pub struct Config {
+    /// Addresses to listen on, `RENDEZVOUS_LISTEN`, separated with commas. Only
+    /// `[::1]:49252` if not set.
+    pub listen: Vec<SocketAddr>,
+    /// Tells this server from the others relaying the same events, `RENDEZVOUS_SERVER_NAME`.
+    /// Required of a server which links to others or lets them link to it.
+    pub name: String,
+    /// HTTP/2 PING interval, `RENDEZVOUS_KEEPALIVE_INTERVAL` in seconds.
+    pub keepalive_interval: Duration,
+    /// How long to wait for a PING response, `RENDEZVOUS_KEEPALIVE_TIMEOUT` in seconds.
+    pub keepalive_timeout: Duration,
+    /// `RENDEZVOUS_HEARTBEAT_INTERVAL` in seconds.
+    pub heartbeat_interval: Duration,
+    /// Subscribers are evicted after missing this many heartbeats in a row, `0` to never
+    /// evict them. `RENDEZVOUS_MAX_MISSED_HEARTBEATS`.
+    pub max_missed_heartbeats: u32,
+    /// How long to wait for subscribers to receive queued events on shutdown,
+    /// `RENDEZVOUS_DRAIN_TIMEOUT` in seconds.
+    pub drain_timeout: Duration,
+    /// Where to serve Prometheus metrics, `RENDEZVOUS_METRICS_ADDR`. Disabled if not set.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Bearer token for `AdminService`, `RENDEZVOUS_ADMIN_TOKEN`. The service is not served
+    /// if not set.
+    pub admin_token: Option<String>,
+    /// SQLite database to keep the chat history in, `RENDEZVOUS_DATABASE`. Only the latest
+    /// [`MemoryStorage::DEFAULT_MAX_EVENTS`](crate::storage::MemoryStorage::DEFAULT_MAX_EVENTS)
+    /// events are kept, in memory, if not set.
+    pub database: Option<PathBuf>,
+    /// Redis server to share the events with the other servers of the same name over, like
+    /// `redis://localhost:6379`, `RENDEZVOUS_REDIS_URL`. The server runs alone if not set.
+    pub redis_url: Option<String>,
+    /// Bearer token which other servers link to this one with, `RENDEZVOUS_PEER_TOKEN`. No
+    /// server can link if not set.
+    pub peer_token: Option<String>,
+    /// Servers to link to, `RENDEZVOUS_PEERS`, separated with `;`.
+    pub peers: Vec<Peer>,
+    /// Certificate chain and private key in PEM to serve TLS with, `RENDEZVOUS_TLS_CERT` and
+    /// `RENDEZVOUS_TLS_KEY`. Plain HTTP/2 is served if not set, which should only be reached
+    /// through a tunnel or a proxy serving TLS by the servers linking to this one.
+    pub tls: Option<(PathBuf, PathBuf)>,
+    /// `RENDEZVOUS_RETENTION_DAYS` and `RENDEZVOUS_RETENTION_MAX_EVENTS`, both unlimited if not
+    /// set.
+    pub retention: Retention,
+    /// Events kept for an offline bouncer longer than this are summarized when it is back,
+    /// `RENDEZVOUS_OUTBOX_MAX_AGE` in minutes.
+    pub outbox_max_age: Duration,
+}
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Config {
+            listen: env_or("RENDEZVOUS_LISTEN", DEFAULT_LISTEN.to_owned())?
+                .split(',')
+                .filter(|addr| !addr.trim().is_empty())
+                .map(|addr| addr.trim().parse())
+                .collect::<Result<_, _>>()
+                .context("invalid RENDEZVOUS_LISTEN")?,
+            name: env_or("RENDEZVOUS_SERVER_NAME", hub::DEFAULT_NAME.to_owned())?,
+            keepalive_interval: Duration::from_secs(env_or(
+                "RENDEZVOUS_KEEPALIVE_INTERVAL",
+                server::DEFAULT_KEEPALIVE_INTERVAL.as_secs(),
+            )?),
+            keepalive_timeout: Duration::from_secs(env_or(
+                "RENDEZVOUS_KEEPALIVE_TIMEOUT",
+                server::DEFAULT_KEEPALIVE_TIMEOUT.as_secs(),
+            )?),
+            heartbeat_interval: Duration::from_secs(env_or(
+                "RENDEZVOUS_HEARTBEAT_INTERVAL",
+                server::DEFAULT_HEARTBEAT_INTERVAL.as_secs(),
+            )?),
+            max_missed_heartbeats: env_or(
+                "RENDEZVOUS_MAX_MISSED_HEARTBEATS",
+                server::DEFAULT_MAX_MISSED_HEARTBEATS,
+            )?,
+            drain_timeout: Duration::from_secs(env_or(
+                "RENDEZVOUS_DRAIN_TIMEOUT",
+                server::DEFAULT_DRAIN_TIMEOUT.as_secs(),
+            )?),
+            metrics_addr: env_opt("RENDEZVOUS_METRICS_ADDR")?,
+            admin_token: env_opt("RENDEZVOUS_ADMIN_TOKEN")?,
+            database: env_opt("RENDEZVOUS_DATABASE")?,
+            redis_url: env_opt("RENDEZVOUS_REDIS_URL")?,
+            peer_token: env_opt("RENDEZVOUS_PEER_TOKEN")?,
+            peers: env_opt::<String>("RENDEZVOUS_PEERS")?
+                .iter()
+                .flat_map(|peers| peers.split(';'))
+                .filter(|peer| !peer.trim().is_empty())
+                .map(str::parse)
+                .collect::<anyhow::Result<_>>()
+                .context("invalid RENDEZVOUS_PEERS")?,
+            tls: match (
+                env_opt("RENDEZVOUS_TLS_CERT")?,
+                env_opt("RENDEZVOUS_TLS_KEY")?,
+            ) {
+                (Some(cert), Some(key)) => Some((cert, key)),
+                (None, None) => None,
+                _ => anyhow::bail!("set both of RENDEZVOUS_TLS_CERT and RENDEZVOUS_TLS_KEY"),
+            },
+            retention: Retention {
+                max_age: env_opt("RENDEZVOUS_RETENTION_DAYS")?
+                    .map(|days: u64| Duration::from_secs(days * 24 * 60 * 60)),
+                max_events: env_opt("RENDEZVOUS_RETENTION_MAX_EVENTS")?,
+            },
+            outbox_max_age: Duration::from_secs(
+                env_or(
+                    "RENDEZVOUS_OUTBOX_MAX_AGE",
+                    hub::DEFAULT_OUTBOX_MAX_AGE.as_secs() / 60,
+                )? * 60,
+            ),
+        })
+    }
+}
+
+fn env_or<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: Error + Send + Sync + 'static,
+{
+    Ok(env_opt(name)?.unwrap_or(default))
+}
+
+fn env_opt<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(
+            value.parse().with_context(|| format!("invalid {}", name))?,
+        )),
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tonic_health::{server::HealthReporter, ServingStatus};
+
+use rendezvous_common::{
+    proto::bouncer_service_server::BouncerServiceServer,
+    tokio,
+    tonic::transport::NamedService,
+    tracing::warn,
+};
+
+use crate::{
+    hub::Hub,
+    storage::{self, Storage},
+    BouncerServiceImpl,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Keeps the gRPC health status of the server up to date.
+///
+/// Both the server as a whole (`""`) and `BouncerService` are reported as serving while the
+/// hub accepts events and the history can be stored.
+pub async fn report(mut reporter: HealthReporter, hub: Arc<Hub>, storage: Arc<dyn Storage>) {
+    let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let storage_ok = check_storage(&storage).await;
+        // The server reports itself as not serving once it drains, for good.
+        if hub.is_draining() {
+            return;
+        }
+        let status = if storage_ok {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+        set_status(&mut reporter, status).await;
+    }
+}
+
+async fn check_storage(storage: &Arc<dyn Storage>) -> bool {
+    match storage::blocking(storage, |s| s.check()).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("storage is unavailable: {:?}", e);
+            false
+        }
+    }
+}
+
+pub async fn set_status(reporter: &mut HealthReporter, status: ServingStatus) {
+    reporter.set_service_status("", status).await;
+    reporter
+        .set_service_status(
+            <BouncerServiceServer<BouncerServiceImpl> as NamedService>::NAME,
+            status,
+        )
+        .await;
+}
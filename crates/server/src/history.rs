@@ -0,0 +1,155 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use rendezvous_common::{
+    anyhow,
+    prost_types::Timestamp,
+    proto::{
+        history_service_server::HistoryService, HistoryEvent, HistoryRequest, HistoryResponse,
+        ListChannelsRequest, ListChannelsResponse, SearchRequest, SearchResponse,
+    },
+    tonic::{self, Request, Response, Status},
+    tracing::{error, instrument},
+};
+
+use crate::storage::{self, Cursor, HistoryQuery, Position, SearchQuery, Storage, StoredEvent};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+#[derive(Debug)]
+pub struct HistoryServiceImpl {
+    storage: Arc<dyn Storage>,
+}
+
+impl HistoryServiceImpl {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        HistoryServiceImpl { storage }
+    }
+
+    async fn query<F, T>(&self, f: F) -> Result<T, Status>
+    where
+        F: FnOnce(&dyn Storage) -> anyhow::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        storage::blocking(&self.storage, f).await.map_err(|e| {
+            error!("failed to read the history: {:?}", e);
+            Status::internal("failed to read the history")
+        })
+    }
+
+    /// Takes the sequence numbers which were the cursors before the history was ordered by
+    /// time as well.
+    async fn cursor(&self, cursor: &str) -> Result<Option<Position>, Status> {
+        let seq = match cursor.parse::<u64>() {
+            Ok(seq) => seq,
+            Err(_) => return parse_cursor(cursor),
+        };
+        match self.query(move |storage| storage.position(seq)).await? {
+            Some(position) => Ok(Some(position)),
+            None => Err(Status::invalid_argument(format!(
+                "no such cursor, or the event is gone: {}",
+                cursor
+            ))),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl HistoryService for HistoryServiceImpl {
+    #[instrument(skip(self))]
+    async fn get_history(
+        &self,
+        request: Request<HistoryRequest>,
+    ) -> Result<Response<HistoryResponse>, Status> {
+        let request = request.into_inner();
+        let cursor = match (
+            self.cursor(&request.before).await?,
+            self.cursor(&request.after).await?,
+        ) {
+            (None, None) if request.earliest => Cursor::Earliest,
+            (None, None) => Cursor::Latest,
+            (Some(before), None) => Cursor::Before(before),
+            (None, Some(after)) => Cursor::After(after),
+            (Some(_), Some(_)) => {
+                return Err(Status::invalid_argument(
+                    "only one of before and after can be set",
+                ))
+            }
+        };
+        let query = HistoryQuery {
+            channel: request.channel,
+            since: request.since.map(parse_time).transpose()?,
+            until: request.until.map(parse_time).transpose()?,
+            cursor,
+            limit: limit(request.limit),
+        };
+        let page = self.query(move |storage| storage.history(&query)).await?;
+        Ok(Response::new(HistoryResponse {
+            events: page.events.into_iter().map(history_event).collect(),
+            more: page.more,
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn search(
+        &self,
+        request: Request<SearchRequest>,
+    ) -> Result<Response<SearchResponse>, Status> {
+        let request = request.into_inner();
+        let query = SearchQuery {
+            terms: SearchQuery::terms(&request.query),
+            sources: request.sources().collect(),
+            channels: request.channels,
+            nicknames: request.nicknames,
+            before: self.cursor(&request.before).await?,
+            limit: limit(request.limit),
+        };
+        let page = self.query(move |storage| storage.search(&query)).await?;
+        Ok(Response::new(SearchResponse {
+            events: page.events.into_iter().map(history_event).collect(),
+            more: page.more,
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn list_channels(
+        &self,
+        _request: Request<ListChannelsRequest>,
+    ) -> Result<Response<ListChannelsResponse>, Status> {
+        let channels = self.query(|storage| storage.channels()).await?;
+        Ok(Response::new(ListChannelsResponse { channels }))
+    }
+}
+
+// Both fail with the `Status` the services return.
+#[allow(clippy::result_large_err)]
+fn parse_cursor(cursor: &str) -> Result<Option<Position>, Status> {
+    if cursor.is_empty() {
+        return Ok(None);
+    }
+    cursor
+        .parse()
+        .map(Some)
+        .map_err(|_| Status::invalid_argument(format!("invalid cursor: {}", cursor)))
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_time(time: Timestamp) -> Result<SystemTime, Status> {
+    SystemTime::try_from(time).map_err(|e| Status::invalid_argument(e.to_string()))
+}
+
+fn limit(limit: u32) -> usize {
+    match limit as usize {
+        0 => DEFAULT_LIMIT,
+        limit => limit.min(MAX_LIMIT),
+    }
+}
+
+fn history_event((position, stored): (Position, StoredEvent)) -> HistoryEvent {
+    HistoryEvent {
+        time: Some(stored.time.into()),
+        event: Some(stored.into_event()),
+        cursor: position.to_string(),
+    }
+}
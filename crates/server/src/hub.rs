@@ -0,0 +1,883 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::{Duration, SystemTime};
+
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+use crate::{
+    bus::{Bus, LocalBus},
+    metrics::{self, client_label},
+    routes::Routes,
+    storage::{self, Cursor, HistoryQuery, MemoryStorage, Storage, StoredEvent},
+};
+
+use rendezvous_common::{
+    anyhow,
+    futures::{
+        future::{self, Either},
+        stream::{self, BoxStream},
+        StreamExt, TryStreamExt,
+    },
+    proto::{
+        admin, event, BouncerStatus, BouncerStatusChanged, ClientType, Delivery, DeliveryAck,
+        DeliveryStatus, Event, Heartbeat, Scrollback, SubscriptionFilter, SystemNotice,
+    },
+    recent::RecentIds,
+    record::{Body, Record},
+    tokio::{
+        self,
+        sync::{
+            mpsc::{self, error::TrySendError},
+            oneshot, watch, Mutex, RwLock,
+        },
+        time::Instant,
+    },
+    tonic::Status,
+    tracing::{debug, error, info, warn},
+};
+
+const QUEUE_CAPACITY: usize = 64;
+const STATUS_NOTICE_TIMEOUT: Duration = Duration::from_secs(5);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const BUS_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Events waiting to be shared with the other servers; newer ones are left unshared beyond it.
+const BUS_QUEUE_CAPACITY: usize = 1024;
+pub const MAX_SCROLLBACK_PER_CHANNEL: usize = 500;
+pub const DEFAULT_OUTBOX_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+pub const DEFAULT_NAME: &str = "rendezvous";
+/// Events relayed by more servers than this are dropped, even if this one isn't among them.
+const MAX_HOPS: usize = 8;
+
+pub type EventStream = BoxStream<'static, Result<Event, Status>>;
+
+#[derive(Debug)]
+struct Subscriber {
+    client_type: ClientType,
+    filter: SubscriptionFilter,
+    sender: mpsc::Sender<Result<Event, Status>>,
+    /// Ends the stream at once, even if the queue is full.
+    cancel: Option<oneshot::Sender<Status>>,
+    missed_heartbeats: u32,
+    peer_addr: Option<SocketAddr>,
+    connected_since: SystemTime,
+    events_sent: u64,
+}
+
+impl Subscriber {
+    fn queue_depth(&self) -> usize {
+        QUEUE_CAPACITY - self.sender.capacity()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Subscribers {
+    by_id: HashMap<u64, Subscriber>,
+    /// Bouncer types which have subscribed at least once, with the filter they subscribed with
+    /// last. Kept in the storage as well.
+    known: HashMap<ClientType, SubscriptionFilter>,
+    last_id: u64,
+}
+
+impl Subscribers {
+    /// Ends the stream of the subscription with `reason`.
+    fn remove(&mut self, id: u64, reason: &str) -> Option<Subscriber> {
+        let mut removed = self.by_id.remove(&id)?;
+        if let Some(cancel) = removed.cancel.take() {
+            let _ = cancel.send(Status::unavailable(reason.to_owned()));
+        }
+        let _ = metrics::QUEUE_DEPTH
+            .remove_label_values(&[client_label(removed.client_type), &id.to_string()]);
+        self.record_count(removed.client_type);
+        Some(removed)
+    }
+
+    fn record_count(&self, client_type: ClientType) {
+        let count = self
+            .by_id
+            .values()
+            .filter(|s| s.client_type == client_type)
+            .count();
+        metrics::ACTIVE_SUBSCRIPTIONS
+            .with_label_values(&[client_label(client_type)])
+            .set(count as i64);
+    }
+}
+
+/// Fans posted events out to the subscribers.
+#[derive(Debug)]
+pub struct Hub {
+    /// Added to the hops of the events relayed here.
+    name: String,
+    /// To drop the events which come back around a loop.
+    recent_origins: Mutex<RecentIds>,
+    subscribers: Mutex<Subscribers>,
+    /// Held for reading while keeping an event for offline bouncers, and taken for writing by a
+    /// bouncer subscribing, so that it waits for the events which missed its subscription.
+    outbox_gate: RwLock<()>,
+    routes: Mutex<Routes>,
+    storage: Arc<dyn Storage>,
+    /// Shares the events with the other servers serving the same bouncers.
+    bus: Arc<dyn Bus>,
+    /// The events to be published on the bus, in the background.
+    bus_queue: mpsc::Sender<Event>,
+    /// Taken by `run_bus`.
+    bus_outgoing: std::sync::Mutex<Option<mpsc::Receiver<Event>>>,
+    pending_acks: Mutex<HashMap<(String, ClientType), oneshot::Sender<DeliveryAck>>>,
+    draining: AtomicBool,
+    /// Set when the subscription streams should end.
+    closing: watch::Sender<bool>,
+    /// Events kept longer than this for an offline bouncer are summarized instead of replayed.
+    outbox_max_age: Duration,
+}
+
+impl Default for Hub {
+    /// Keeps the history in memory, and runs alone.
+    fn default() -> Self {
+        Hub::new(
+            DEFAULT_NAME.to_owned(),
+            Arc::new(MemoryStorage::default()),
+            Arc::new(LocalBus),
+            Routes::default(),
+            DEFAULT_OUTBOX_MAX_AGE,
+        )
+    }
+}
+
+impl Hub {
+    pub fn new(
+        name: String,
+        storage: Arc<dyn Storage>,
+        bus: Arc<dyn Bus>,
+        routes: Routes,
+        outbox_max_age: Duration,
+    ) -> Self {
+        let (bus_queue, bus_outgoing) = mpsc::channel(BUS_QUEUE_CAPACITY);
+        Hub {
+            name,
+            recent_origins: Default::default(),
+            subscribers: Default::default(),
+            outbox_gate: Default::default(),
+            routes: Mutex::new(routes),
+            storage,
+            bus,
+            bus_queue,
+            bus_outgoing: std::sync::Mutex::new(Some(bus_outgoing)),
+            pending_acks: Default::default(),
+            draining: AtomicBool::new(false),
+            closing: watch::channel(false).0,
+            outbox_max_age,
+        }
+    }
+
+    /// Picks up the bouncer types which subscribed before, so that events are kept for them
+    /// until they are back.
+    pub async fn load_bouncers(&self) -> anyhow::Result<()> {
+        let bouncers = storage::blocking(&self.storage, |s| s.bouncers()).await?;
+        self.subscribers.lock().await.known.extend(bouncers);
+        Ok(())
+    }
+
+    /// Events from the history come first if `scrollback` asks for them, then the events kept
+    /// for the bouncer while it was offline.
+    pub async fn subscribe(
+        self: &Arc<Self>,
+        client_type: ClientType,
+        filter: SubscriptionFilter,
+        peer_addr: Option<SocketAddr>,
+        scrollback: Option<Scrollback>,
+    ) -> (u64, EventStream) {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let (cancel, cancelled) = oneshot::channel();
+        let id;
+        let mut recovered = false;
+        {
+            let mut subs = self.subscribers.lock().await;
+            if client_type.is_bouncer() {
+                let was_up = subs.by_id.values().any(|s| s.client_type == client_type);
+                recovered = !was_up && subs.known.contains_key(&client_type);
+                // There's only one bouncer per type; a new subscription replaces the old one.
+                let replaced: Vec<_> = subs
+                    .by_id
+                    .iter()
+                    .filter(|(_, s)| s.client_type == client_type)
+                    .map(|(&id, _)| id)
+                    .collect();
+                for id in replaced {
+                    subs.remove(id, "replaced by a new subscription");
+                }
+                subs.known.insert(client_type, filter.clone());
+            }
+            subs.last_id += 1;
+            id = subs.last_id;
+            subs.by_id.insert(
+                id,
+                Subscriber {
+                    client_type,
+                    filter: filter.clone(),
+                    sender,
+                    cancel: Some(cancel),
+                    missed_heartbeats: 0,
+                    peer_addr,
+                    connected_since: SystemTime::now(),
+                    events_sent: 0,
+                },
+            );
+            subs.record_count(client_type);
+        }
+
+        let mut outbox = vec![];
+        if client_type.is_bouncer() {
+            // The events which are being kept as this subscribes are in the outbox once the
+            // gate opens; the later ones go to the new subscription.
+            drop(self.outbox_gate.write().await);
+            let saved = filter.clone();
+            let result = storage::blocking(&self.storage, move |s| {
+                s.save_bouncer(client_type, &saved)?;
+                s.outbox(client_type)
+            })
+            .await;
+            match result {
+                Ok(records) => outbox = records,
+                Err(e) => error!("failed to read the outbox of {:?}: {:?}", client_type, e),
+            }
+        }
+
+        if recovered {
+            info!("{:?} is back", client_type);
+            self.notify_status(client_type, BouncerStatus::Up, "").await;
+        }
+
+        // Read after subscribing, so that nothing falls in between; the live events which were
+        // read as well are skipped.
+        let replayed = match scrollback {
+            Some(scrollback) => self.scrollback(client_type, &filter, &scrollback).await,
+            None => vec![],
+        };
+        let replayed_ids: HashSet<_> = replayed.iter().map(|e| e.id.clone()).collect();
+        if !outbox.is_empty() {
+            info!("{} events were kept for {:?}", outbox.len(), client_type);
+        }
+        let kept_until = outbox.last().map(|&(seq, _)| seq);
+        let records = outbox.into_iter().map(|(_, record)| record).collect();
+        let mut missed = self.missed_events(records, SystemTime::now());
+        missed.retain(|e| !replayed_ids.contains(&e.id));
+        let live = ReceiverStream::new(receiver)
+            .filter(move |e| future::ready(!matches!(e, Ok(e) if replayed_ids.contains(&e.id))));
+        // The kept events are deleted once they have all been sent.
+        let storage = Arc::clone(&self.storage);
+        let sent = stream::iter(kept_until).filter_map(move |seq| {
+            let storage = Arc::clone(&storage);
+            async move {
+                let result =
+                    storage::blocking(&storage, move |s| s.ack_outbox(client_type, seq)).await;
+                if let Err(e) = result {
+                    error!("failed to clear the outbox of {:?}: {:?}", client_type, e);
+                }
+                None
+            }
+        });
+
+        let mut closing = self.closing.subscribe();
+        let stream = stream::iter(replayed.into_iter().chain(missed).map(Ok));
+        let stream = stream.chain(sent).chain(live).take_until(async move {
+            while !*closing.borrow() {
+                if closing.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+        let guard = HangUp {
+            hub: Arc::clone(self),
+            id,
+        };
+        (id, until_cancelled(stream.boxed(), cancelled, guard))
+    }
+
+    /// The stored events which a new subscriber would have received, oldest first, marked as
+    /// replayed.
+    async fn scrollback(
+        &self,
+        client_type: ClientType,
+        filter: &SubscriptionFilter,
+        scrollback: &Scrollback,
+    ) -> Vec<Event> {
+        let per_channel = match scrollback.events_per_channel as usize {
+            0 => MAX_SCROLLBACK_PER_CHANNEL,
+            n => n.min(MAX_SCROLLBACK_PER_CHANNEL),
+        };
+        let since = match scrollback.minutes {
+            0 => None,
+            minutes => Some(SystemTime::now() - Duration::from_secs(u64::from(minutes) * 60)),
+        };
+        let result = storage::blocking(&self.storage, move |storage| {
+            let mut events = vec![];
+            for channel in storage.channels()? {
+                let query = HistoryQuery {
+                    channel,
+                    since,
+                    until: None,
+                    cursor: Cursor::Latest,
+                    limit: per_channel,
+                };
+                events.extend(storage.history(&query)?.events);
+            }
+            events.sort_by_key(|&(position, _)| position);
+            Ok(events)
+        })
+        .await;
+        let events = match result {
+            Ok(events) => events,
+            Err(e) => {
+                error!("failed to read the scrollback: {:?}", e);
+                return vec![];
+            }
+        };
+
+        let routes = self.routes.lock().await;
+        events
+            .into_iter()
+            .map(|(_, stored)| stored.into_event())
+            .filter(|event| {
+                let source = event
+                    .header
+                    .as_ref()
+                    .map(|h| h.client_type())
+                    .unwrap_or_default();
+                !(source == client_type && source != ClientType::Unknown)
+                    && routes.check(source, client_type).is_none()
+                    && filter.matches(event)
+            })
+            .map(|mut event| {
+                event.replayed = true;
+                event
+            })
+            .collect()
+    }
+
+    /// The events kept for an offline bouncer, to be sent once it is back.
+    ///
+    /// Messages older than the maximum age are counted per channel into a notice instead, and
+    /// other older events are left out.
+    fn missed_events(&self, outbox: Vec<Record>, now: SystemTime) -> Vec<Event> {
+        let mut missed = BTreeMap::<String, usize>::new();
+        let mut recent = vec![];
+        for record in outbox {
+            let kept_since = record.received_at.map(SystemTime::from).unwrap_or(now);
+            if kept_since + self.outbox_max_age >= now {
+                recent.push(record.into_event());
+            } else if let Body::MessageCreated { channel, .. } = &record.body {
+                *missed.entry(channel.clone()).or_default() += 1;
+            }
+        }
+        let notices = missed.into_iter().map(|(channel, count)| {
+            let content = format!(
+                "{} message{} missed while the bridge was down",
+                count,
+                if count == 1 { "" } else { "s" }
+            );
+            let mut event = Event::new(
+                ClientType::Unknown,
+                event::Body::SystemNotice(SystemNotice { channel, content }),
+            );
+            event.id = Uuid::new_v4().to_string();
+            event.received_at = Some(now.into());
+            event
+        });
+        // The summarized messages are older than the rest.
+        notices.chain(recent).collect()
+    }
+
+    /// Drops a subscription, telling the other bouncers if it was the last one of a bouncer.
+    ///
+    /// Returns `false` if there was no such subscription.
+    pub async fn unsubscribe(&self, id: u64, reason: &str) -> bool {
+        let (client_type, is_down) = {
+            let mut subs = self.subscribers.lock().await;
+            let removed = match subs.remove(id, reason) {
+                Some(sub) => sub,
+                // Already replaced by a newer subscription.
+                None => return false,
+            };
+            let client_type = removed.client_type;
+            let is_down = client_type.is_bouncer()
+                && !subs.by_id.values().any(|s| s.client_type == client_type);
+            (client_type, is_down)
+        };
+        info!("Disconnected from {:?}: {}", client_type, reason);
+        if is_down && !self.is_draining() {
+            self.notify_status(client_type, BouncerStatus::Down, reason)
+                .await;
+        }
+        true
+    }
+
+    pub async fn subscriptions(&self) -> Vec<admin::Subscription> {
+        let subs = self.subscribers.lock().await;
+        let mut list: Vec<_> = subs
+            .by_id
+            .iter()
+            .map(|(&id, sub)| admin::Subscription {
+                id,
+                client_type: sub.client_type.into(),
+                peer_addr: sub.peer_addr.map(|a| a.to_string()).unwrap_or_default(),
+                connected_since: Some(sub.connected_since.into()),
+                queue_depth: sub.queue_depth() as u32,
+                events_sent: sub.events_sent,
+                filter: Some(sub.filter.clone()),
+            })
+            .collect();
+        list.sort_by_key(|s| s.id);
+        list
+    }
+
+    pub async fn routes(&self) -> Vec<admin::Route> {
+        self.routes.lock().await.list()
+    }
+
+    pub async fn add_route(&self, source: ClientType, destination: ClientType) -> admin::Route {
+        let route = self.routes.lock().await.add(source, destination);
+        info!("route {:?} -> {:?} added", source, destination);
+        route
+    }
+
+    pub async fn remove_route(&self, source: ClientType, destination: ClientType) -> bool {
+        let removed = self.routes.lock().await.remove(source, destination);
+        if removed {
+            info!("route {:?} -> {:?} removed", source, destination);
+        }
+        removed
+    }
+
+    /// Returns `None` if there is no route from `source` to `destination`.
+    pub async fn set_route_paused(
+        &self,
+        source: ClientType,
+        destination: ClientType,
+        paused: bool,
+    ) -> Option<admin::Route> {
+        let route = self
+            .routes
+            .lock()
+            .await
+            .set_paused(source, destination, paused)?;
+        info!(
+            "route {:?} -> {:?} {}",
+            source,
+            destination,
+            if paused { "paused" } else { "resumed" }
+        );
+        Some(route)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Marks the hub as draining, so that it's no longer reported as serving.
+    pub fn begin_drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Marks the hub as draining and waits up to `timeout` for the subscribers to receive the
+    /// events queued for them, then ends every subscription stream.
+    ///
+    /// Returns the number of events left undelivered.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        self.begin_drain();
+        let deadline = Instant::now() + timeout;
+        let undelivered = loop {
+            let queued: usize = {
+                let subs = self.subscribers.lock().await;
+                subs.by_id.values().map(Subscriber::queue_depth).sum()
+            };
+            if queued == 0 || Instant::now() >= deadline {
+                break queued;
+            }
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        };
+        let _ = self.closing.send(true);
+        undelivered
+    }
+
+    /// Sends a heartbeat to every subscriber each `interval`, evicting the ones which left
+    /// `max_missed` heartbeats in a row unanswered.
+    pub async fn run_heartbeats(self: Arc<Self>, interval: Duration, max_missed: u32) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        let mut seq = 0;
+        loop {
+            ticker.tick().await;
+            seq += 1;
+            let mut dead = vec![];
+            {
+                let mut subs = self.subscribers.lock().await;
+                for (&id, sub) in &mut subs.by_id {
+                    if max_missed > 0 && sub.missed_heartbeats >= max_missed {
+                        dead.push(id);
+                        continue;
+                    }
+                    sub.missed_heartbeats += 1;
+                    let heartbeat = Event::new(
+                        ClientType::Unknown,
+                        event::Body::Heartbeat(Heartbeat {
+                            subscription_id: id,
+                            seq,
+                        }),
+                    );
+                    // A full queue counts as a missed heartbeat as well.
+                    let _ = sub.sender.try_send(Ok(heartbeat));
+                }
+            }
+            for id in dead {
+                let reason = format!("missed {} heartbeats", max_missed);
+                self.unsubscribe(id, &reason).await;
+            }
+        }
+    }
+
+    pub async fn ack_heartbeat(&self, heartbeat: &Heartbeat) {
+        let mut subs = self.subscribers.lock().await;
+        if let Some(sub) = subs.by_id.get_mut(&heartbeat.subscription_id) {
+            sub.missed_heartbeats = 0;
+        }
+    }
+
+    async fn notify_status(&self, client_type: ClientType, status: BouncerStatus, reason: &str) {
+        let mut event = Event::new(
+            client_type,
+            event::Body::BouncerStatusChanged(BouncerStatusChanged {
+                client_type: client_type.into(),
+                status: status.into(),
+                reason: reason.to_owned(),
+            }),
+        );
+        event.id = Uuid::new_v4().to_string();
+        event.received_at = Some(SystemTime::now().into());
+        self.stamp(&mut event).await;
+        self.publish(client_type, &event, STATUS_NOTICE_TIMEOUT)
+            .await;
+    }
+
+    /// Adds this server to the hops of `event`, which gets its id as the origin if it has none.
+    ///
+    /// Returns `false` if the event was relayed here before, or by too many servers; it should
+    /// be dropped then, so that it doesn't go around in a loop.
+    pub async fn stamp(&self, event: &mut Event) -> bool {
+        if event.origin_id.is_empty() {
+            event.origin_id = event.id.clone();
+        }
+        if event.hops.contains(&self.name)
+            || event.hops.len() >= MAX_HOPS
+            || !self.recent_origins.lock().await.insert(&event.origin_id)
+        {
+            warn!(
+                "dropping {}, which was relayed before by {:?}",
+                event.origin_id, event.hops
+            );
+            metrics::EVENTS_LOOPED.inc();
+            return false;
+        }
+        event.hops.push(self.name.clone());
+        true
+    }
+
+    /// Stores `event` in the history and relays it to every subscriber but the bouncer which
+    /// posted it, as far as the routes and their filters accept it, and then to the other
+    /// servers on the bus.
+    ///
+    /// If the event asks for delivery acknowledgements, waits up to `timeout` for the
+    /// bouncers to report back.
+    pub async fn publish(
+        &self,
+        source: ClientType,
+        event: &Event,
+        timeout: Duration,
+    ) -> Vec<Delivery> {
+        let received_at = event.received_at.clone().and_then(|t| t.try_into().ok());
+        if let Some(stored) = StoredEvent::new(received_at.unwrap_or_else(SystemTime::now), event) {
+            // The event is relayed all the same.
+            if let Err(e) = storage::blocking(&self.storage, move |s| s.store(&stored)).await {
+                error!("failed to store {}: {:?}", event.id, e);
+            }
+        }
+        self.fan_out(source, event, timeout, true).await
+    }
+
+    /// Publishes the events posted here on the bus, and hands the events posted to the other
+    /// servers to the subscribers here, subscribing to the bus again whenever it breaks.
+    pub async fn run_bus(self: Arc<Self>) {
+        let outgoing = self.bus_outgoing.lock().unwrap().take();
+        let publish = async {
+            let mut outgoing = match outgoing {
+                Some(outgoing) => outgoing,
+                // Run before.
+                None => return,
+            };
+            while let Some(event) = outgoing.recv().await {
+                if let Err(e) = self.bus.publish(&event).await {
+                    error!(
+                        "failed to share {} with the other servers: {:?}",
+                        event.id, e
+                    );
+                }
+            }
+        };
+        future::join(publish, self.receive_from_bus()).await;
+    }
+
+    async fn receive_from_bus(&self) {
+        loop {
+            let mut events = self.bus.subscribe();
+            loop {
+                match events.try_next().await {
+                    Ok(Some(mut event)) => {
+                        // The server it was posted to has stamped it already. Published
+                        // again, or relayed here by a peer as well.
+                        if !self.recent_origins.lock().await.insert(&event.origin_id) {
+                            debug!("dropping {} from the bus, seen before", event.origin_id);
+                            continue;
+                        }
+                        // Only that server waits for the delivery reports.
+                        event.options = None;
+                        // Kept in the history here as well, so that each server has all of it.
+                        let received_at = event.received_at.clone().and_then(|t| t.try_into().ok());
+                        let time = received_at.unwrap_or_else(SystemTime::now);
+                        if let Some(stored) = StoredEvent::new(time, &event) {
+                            let result = storage::blocking(&self.storage, move |s| {
+                                s.import(std::slice::from_ref(&stored))
+                            })
+                            .await;
+                            if let Err(e) = result {
+                                error!("failed to store {}: {:?}", event.id, e);
+                            }
+                        }
+                        let source = event.header().map(|h| h.client_type()).unwrap_or_default();
+                        self.fan_out(source, &event, Duration::ZERO, false).await;
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("the bus broke: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            tokio::time::sleep(BUS_RETRY_INTERVAL).await;
+        }
+    }
+
+    /// Sends `event` to the subscribers here and, if it was `posted_here`, keeps it for the
+    /// offline bouncers and shares it with the other servers. Then waits for the delivery
+    /// reports it asks for.
+    async fn fan_out(
+        &self,
+        source: ClientType,
+        event: &Event,
+        timeout: Duration,
+        posted_here: bool,
+    ) -> Vec<Delivery> {
+        let wait = event.wants_ack();
+        let mut deliveries = vec![];
+        let mut acks = vec![];
+        // Offline bouncers which the event would have been sent to, by delivery.
+        let mut deferred = vec![];
+        let mut kept = None;
+        let timer = metrics::FANOUT_DURATION.start_timer();
+        {
+            let mut subs = self.subscribers.lock().await;
+            let routes = self.routes.lock().await;
+            let mut pending = self.pending_acks.lock().await;
+            let mut reached = HashSet::new();
+            for (&id, sub) in &mut subs.by_id {
+                // Bouncers don't get their own events back; other clients see everything.
+                if sub.client_type == source && source != ClientType::Unknown {
+                    continue;
+                }
+                reached.insert(sub.client_type);
+                let status = if let Some(status) = routes.check(source, sub.client_type) {
+                    status
+                } else if !sub.filter.matches(event) {
+                    DeliveryStatus::Filtered
+                } else {
+                    match sub.sender.try_send(Ok(event.clone())) {
+                        Ok(()) => {
+                            sub.events_sent += 1;
+                            DeliveryStatus::Queued
+                        }
+                        Err(TrySendError::Full(_)) => {
+                            warn!(
+                                "{:?}: queue is full, dropping {}",
+                                sub.client_type, event.id
+                            );
+                            metrics::EVENTS_DROPPED
+                                .with_label_values(&[client_label(sub.client_type)])
+                                .inc();
+                            DeliveryStatus::Dropped
+                        }
+                        // Dropped along with its stream shortly.
+                        Err(TrySendError::Closed(_)) => {
+                            deferred.push((deliveries.len(), sub.client_type));
+                            DeliveryStatus::Offline
+                        }
+                    }
+                };
+                if wait && status == DeliveryStatus::Queued && sub.client_type.is_bouncer() {
+                    let (tx, rx) = oneshot::channel();
+                    pending.insert((event.id.clone(), sub.client_type), tx);
+                    acks.push((deliveries.len(), rx));
+                }
+                metrics::QUEUE_DEPTH
+                    .with_label_values(&[client_label(sub.client_type), &id.to_string()])
+                    .set(sub.queue_depth() as i64);
+                deliveries.push(Delivery::new(sub.client_type, id, status));
+            }
+            for (&client_type, filter) in &subs.known {
+                if client_type != source && !reached.contains(&client_type) {
+                    if routes.check(source, client_type).is_none() && filter.matches(event) {
+                        deferred.push((deliveries.len(), client_type));
+                    }
+                    deliveries.push(Delivery::new(client_type, 0, DeliveryStatus::Offline));
+                }
+            }
+            if posted_here && !deferred.is_empty() {
+                if let Some(record) = Record::from_event(event).filter(is_kept_offline) {
+                    // Entered while holding the lock, so that a bouncer subscribing again waits
+                    // for the event to be kept.
+                    kept = Some((record, self.outbox_gate.read().await));
+                }
+            }
+        }
+        if let Some((record, _gate)) = kept {
+            let destinations: Vec<_> = deferred.iter().map(|&(_, d)| d).collect();
+            let result = storage::blocking(&self.storage, move |s| {
+                destinations
+                    .into_iter()
+                    .try_for_each(|d| s.push_outbox(d, &record))
+            })
+            .await;
+            match result {
+                Ok(()) => {
+                    for (idx, _) in deferred {
+                        deliveries[idx].set_status(DeliveryStatus::Deferred);
+                    }
+                }
+                Err(e) => error!("failed to keep {} for later: {:?}", event.id, e),
+            }
+        }
+        timer.observe_duration();
+        // Without waiting for the other servers, which may be unreachable.
+        if posted_here {
+            if let Err(TrySendError::Full(_)) = self.bus_queue.try_send(event.clone()) {
+                warn!(
+                    "the bus is behind, not sharing {} with the other servers",
+                    event.id
+                );
+            }
+        }
+        if deliveries.is_empty() {
+            deliveries.push(Delivery::new(
+                ClientType::Unknown,
+                0,
+                DeliveryStatus::NoRoute,
+            ));
+        }
+        if acks.is_empty() {
+            return deliveries;
+        }
+
+        let results = future::join_all(
+            acks.into_iter()
+                .map(|(idx, rx)| async move { (idx, tokio::time::timeout(timeout, rx).await) }),
+        )
+        .await;
+        for (idx, result) in results {
+            let delivery = &mut deliveries[idx];
+            match result {
+                Ok(Ok(ack)) if ack.success => delivery.set_status(DeliveryStatus::Delivered),
+                Ok(Ok(ack)) => {
+                    delivery.set_status(DeliveryStatus::Failed);
+                    delivery.reason = ack.reason;
+                }
+                Ok(Err(_)) => {
+                    delivery.set_status(DeliveryStatus::Failed);
+                    delivery.reason = "subscription closed".to_owned();
+                }
+                Err(_) => delivery.set_status(DeliveryStatus::TimedOut),
+            }
+        }
+        self.pending_acks
+            .lock()
+            .await
+            .retain(|(event_id, _), _| event_id != &event.id);
+        deliveries
+    }
+
+    /// Hands a delivery report to the `publish` call waiting for it.
+    ///
+    /// Returns `false` if nobody is waiting for the report.
+    pub async fn ack(&self, ack: DeliveryAck) -> bool {
+        let client_type = ack
+            .header
+            .as_ref()
+            .map(|h| h.client_type())
+            .unwrap_or_default();
+        let waiter = self
+            .pending_acks
+            .lock()
+            .await
+            .remove(&(ack.event_id.clone(), client_type));
+        match waiter {
+            Some(tx) => tx.send(ack).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Ends `events` as soon as `cancelled` gives the reason, or the subscription is dropped from
+/// the hub without one, however many events are queued.
+fn until_cancelled(
+    events: EventStream,
+    cancelled: oneshot::Receiver<Status>,
+    guard: HangUp,
+) -> EventStream {
+    stream::unfold(Some((events, cancelled, guard)), |state| async move {
+        let (mut events, mut cancelled, guard) = state?;
+        // The reason goes first, since the queue closes along with it.
+        let next = match future::select(&mut cancelled, events.next()).await {
+            Either::Left((reason, _)) => Err(reason.ok()),
+            Either::Right((event, _)) => Ok(event),
+        };
+        match next {
+            Ok(Some(event)) => Some((event, Some((events, cancelled, guard)))),
+            Ok(None) | Err(None) => None,
+            Err(Some(status)) => Some((Err(status), None)),
+        }
+    })
+    .boxed()
+}
+
+/// Drops the subscription once its stream is dropped, when the subscriber hangs up.
+struct HangUp {
+    hub: Arc<Hub>,
+    id: u64,
+}
+
+impl Drop for HangUp {
+    fn drop(&mut self) {
+        let hub = Arc::clone(&self.hub);
+        let id = self.id;
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move { hub.unsubscribe(id, "stream closed").await });
+        }
+    }
+}
+
+/// Whether to keep `record` for the bouncers which are offline; they learn about each other's
+/// status when they subscribe, so that's left out.
+fn is_kept_offline(record: &Record) -> bool {
+    !matches!(record.body, Body::BouncerStatusChanged { .. })
+}
@@ -0,0 +1,161 @@
+//! The Rendezvous server, which relays events between bouncers, as a library to embed in other
+//! programs. [`ServerBuilder`] sets one up and starts it.
+
+#![warn(clippy::all)]
+
+mod admin;
+pub mod bus;
+pub mod config;
+mod health;
+mod history;
+pub mod hub;
+mod metrics;
+pub mod peer;
+pub mod routes;
+mod server;
+pub mod storage;
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use uuid::Uuid;
+
+use rendezvous_common::{
+    proto::{
+        bouncer_service_server::BouncerService, AckResult, ClientType, Delivery, DeliveryAck,
+        DeliveryStatus, Event, Heartbeat, PostResult, Scrollback, SubscribeRequest,
+    },
+    tonic::{self, metadata::MetadataMap, Request, Response, Status},
+    tracing::{self, debug, instrument, Span},
+};
+
+use crate::{
+    hub::{EventStream, Hub},
+    metrics::client_label,
+};
+
+pub use server::{Middleware, ServerBuilder, ServerHandle};
+
+pub(crate) const DEFAULT_DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Posts can't keep a handler waiting for longer than this.
+pub(crate) const MAX_DELIVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct BouncerServiceImpl {
+    hub: Arc<Hub>,
+    /// Which other servers take events as peers with.
+    peer_token: Option<String>,
+}
+
+impl BouncerServiceImpl {
+    pub fn new(hub: Arc<Hub>, peer_token: Option<String>) -> Self {
+        BouncerServiceImpl { hub, peer_token }
+    }
+
+    fn is_authorized(&self, client_type: ClientType, metadata: &MetadataMap) -> bool {
+        client_type != ClientType::Peer || peer::is_authorized(metadata, self.peer_token.as_deref())
+    }
+}
+
+#[tonic::async_trait]
+impl BouncerService for BouncerServiceImpl {
+    type SubscribeStream = EventStream;
+
+    #[instrument(skip(self))]
+    async fn post(&self, request: Request<Event>) -> Result<Response<PostResult>, Status> {
+        debug!("{:?}", request);
+        if self.hub.is_draining() {
+            return Err(Status::unavailable("server is shutting down"));
+        }
+        tracing::set_parent(
+            &Span::current(),
+            &tracing::MetadataExtractor(request.metadata()),
+        );
+        let source = request.get_ref().header()?.client_type();
+        if !self.is_authorized(source, request.metadata()) {
+            return Err(Status::unauthenticated("invalid peer token"));
+        }
+        let mut event = request.into_inner();
+        event.id = Uuid::new_v4().to_string();
+        event.received_at = Some(SystemTime::now().into());
+        event.replayed = false;
+        // Only the linked servers relay events, and only they and the bouncers know where the
+        // events first happened.
+        if source != ClientType::Peer {
+            event.hops.clear();
+            if !source.is_bouncer() {
+                event.origin_id.clear();
+            }
+        }
+        debug!("assigned id {}", event.id);
+        if !self.hub.stamp(&mut event).await {
+            let mut result = PostResult::new(event.id);
+            result.deliveries.push(Delivery::new(
+                ClientType::Unknown,
+                0,
+                DeliveryStatus::Looped,
+            ));
+            return Ok(Response::new(result));
+        }
+        // Subscribers continue the trace from here.
+        event.trace_context.clear();
+        tracing::inject(&mut event.trace_context);
+        metrics::EVENTS_POSTED
+            .with_label_values(&[client_label(source), event.channel().unwrap_or("")])
+            .inc();
+        let timeout = match event.options.as_ref().map(|o| o.delivery_timeout_ms) {
+            Some(ms) if ms > 0 => Duration::from_millis(ms.into()).min(MAX_DELIVERY_TIMEOUT),
+            _ => DEFAULT_DELIVERY_TIMEOUT,
+        };
+        let mut result = PostResult::new(event.id.clone());
+        result.deliveries = self.hub.publish(source, &event, timeout).await;
+        Ok(Response::new(result))
+    }
+
+    #[instrument(skip(self))]
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        debug!("{:?}", request);
+        if self.hub.is_draining() {
+            return Err(Status::unavailable("server is shutting down"));
+        }
+        let peer_addr = request.remote_addr();
+        let client_type = request.get_ref().header()?.client_type();
+        if !self.is_authorized(client_type, request.metadata()) {
+            return Err(Status::unauthenticated("invalid peer token"));
+        }
+        let request = request.into_inner();
+        let filter = request.filter.unwrap_or_default();
+        if let Some(Scrollback {
+            events_per_channel: 0,
+            minutes: 0,
+        }) = request.scrollback
+        {
+            return Err(Status::invalid_argument("the scrollback has no limit"));
+        }
+        let (_, stream) = self
+            .hub
+            .subscribe(client_type, filter, peer_addr, request.scrollback)
+            .await;
+        Ok(Response::new(stream))
+    }
+
+    #[instrument(skip(self))]
+    async fn ack(&self, request: Request<DeliveryAck>) -> Result<Response<AckResult>, Status> {
+        debug!("{:?}", request);
+        if !self.hub.ack(request.into_inner()).await {
+            debug!("nobody is waiting for the report");
+        }
+        Ok(Response::new(AckResult {}))
+    }
+
+    async fn ack_heartbeat(
+        &self,
+        request: Request<Heartbeat>,
+    ) -> Result<Response<AckResult>, Status> {
+        self.hub.ack_heartbeat(request.get_ref()).await;
+        Ok(Response::new(AckResult {}))
+    }
+}
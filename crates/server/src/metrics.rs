@@ -0,0 +1,69 @@
+use rendezvous_common::{
+    metrics::{
+        exponential_buckets, register_histogram, register_int_counter, register_int_counter_vec,
+        register_int_gauge_vec, Histogram, IntCounter, IntCounterVec, IntGaugeVec, Lazy,
+    },
+    proto::ClientType,
+};
+
+pub static EVENTS_POSTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "rendezvous_events_posted_total",
+        "Events posted to the server.",
+        &["source", "channel"]
+    )
+    .unwrap()
+});
+
+pub static FANOUT_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "rendezvous_fanout_duration_seconds",
+        "Time taken to queue a posted event for every subscriber.",
+        exponential_buckets(0.00001, 4.0, 10).unwrap()
+    )
+    .unwrap()
+});
+
+pub static QUEUE_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "rendezvous_subscriber_queue_depth",
+        "Events waiting to be sent to a subscriber.",
+        &["client_type", "subscription"]
+    )
+    .unwrap()
+});
+
+pub static EVENTS_DROPPED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "rendezvous_events_dropped_total",
+        "Events dropped because the subscriber's queue was full.",
+        &["destination"]
+    )
+    .unwrap()
+});
+
+pub static EVENTS_LOOPED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "rendezvous_events_looped_total",
+        "Events dropped because they were relayed here before."
+    )
+    .unwrap()
+});
+
+pub static ACTIVE_SUBSCRIPTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "rendezvous_active_subscriptions",
+        "Open subscription streams.",
+        &["client_type"]
+    )
+    .unwrap()
+});
+
+pub fn client_label(client_type: ClientType) -> &'static str {
+    match client_type {
+        ClientType::Unknown => "unknown",
+        ClientType::Irc => "irc",
+        ClientType::Discord => "discord",
+        ClientType::Peer => "peer",
+    }
+}
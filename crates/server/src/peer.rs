@@ -0,0 +1,188 @@
+//! Links to other servers, whose events are relayed here as if a bouncer had posted them.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use uuid::Uuid;
+
+use rendezvous_common::{
+    anyhow::{self, Context},
+    futures::TryStreamExt,
+    proto::{
+        bouncer_service_client::BouncerServiceClient, event, ClientType, EventKind, Header,
+        SubscribeRequest, SubscriptionFilter,
+    },
+    tokio,
+    tonic::{
+        metadata::{AsciiMetadataValue, MetadataMap},
+        transport::Uri,
+        Request,
+    },
+    tracing::{self, info, warn},
+};
+
+use crate::{admin::constant_time_eq, hub::Hub, DEFAULT_DELIVERY_TIMEOUT};
+
+const RELINK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Another server to relay the events of.
+///
+/// Written as its URL, the token it takes from peers, and the glob patterns of the channels to
+/// relay, separated by spaces; every channel is relayed if none is given.
+///
+/// The token goes along with the requests, so the URL should be `https://`; a server which
+/// doesn't serve TLS itself can be reached through a tunnel, like SSH port forwarding, as
+/// `http://` on a loopback address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Peer {
+    pub url: String,
+    pub token: String,
+    pub channels: Vec<String>,
+}
+
+impl FromStr for Peer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let url = match words.next() {
+            Some(url) => url.to_owned(),
+            None => anyhow::bail!("missing the URL of a peer"),
+        };
+        let token = match words.next() {
+            Some(token) => token.to_owned(),
+            None => anyhow::bail!("missing the token of {}", url),
+        };
+        check_url(&url)?;
+        Ok(Peer {
+            url,
+            token,
+            channels: words.map(str::to_owned).collect(),
+        })
+    }
+}
+
+/// Fails for the URLs which would send the token in the clear to another host.
+pub fn check_url(url: &str) -> anyhow::Result<()> {
+    let uri: Uri = url
+        .parse()
+        .with_context(|| format!("invalid URL of a peer: {}", url))?;
+    let is_loopback = match uri.host() {
+        Some("localhost") => true,
+        Some(host) => matches!(
+            host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>(),
+            Ok(ip) if ip.is_loopback()
+        ),
+        None => anyhow::bail!("missing the host in {}", url),
+    };
+    match uri.scheme_str() {
+        Some("https") => Ok(()),
+        Some("http") if is_loopback => Ok(()),
+        Some("http") => anyhow::bail!(
+            "{} would carry the peer token in the clear, use https:// or a tunnel to localhost",
+            url
+        ),
+        _ => anyhow::bail!("not an http:// or https:// URL: {}", url),
+    }
+}
+
+/// Whether `metadata` carries `authorization: Bearer <token>`. Nobody is authorized if there's
+/// no token.
+pub fn is_authorized(metadata: &MetadataMap, token: Option<&str>) -> bool {
+    match (metadata.get("authorization"), token) {
+        (Some(value), Some(token)) => {
+            constant_time_eq(value.as_bytes(), format!("Bearer {}", token).as_bytes())
+        }
+        _ => false,
+    }
+}
+
+/// Relays the events of `peer` to the subscribers here, linking again whenever the link breaks.
+///
+/// The events the peer got from its own links aren't relayed, so every two servers to bridge
+/// need a link of their own.
+pub async fn link(hub: Arc<Hub>, peer: Peer) {
+    loop {
+        if let Err(e) = relay(&hub, &peer).await {
+            warn!("the link to {} broke: {:?}", peer.url, e);
+        }
+        tokio::time::sleep(RELINK_INTERVAL).await;
+    }
+}
+
+async fn relay(hub: &Hub, peer: &Peer) -> anyhow::Result<()> {
+    let authorization: AsciiMetadataValue = format!("Bearer {}", peer.token).parse()?;
+    let channel = rendezvous_common::endpoint(&peer.url)?.connect().await?;
+    let mut client = BouncerServiceClient::new(channel);
+    let mut request = Request::new(SubscribeRequest {
+        filter: Some(SubscriptionFilter {
+            channels: peer.channels.clone(),
+            // The bouncers there going up and down means nothing here.
+            kinds: vec![
+                EventKind::MessageCreated.into(),
+                EventKind::UserRenamed.into(),
+                EventKind::SystemNotice.into(),
+                EventKind::MembershipChanged.into(),
+            ],
+            ..Default::default()
+        }),
+        ..SubscribeRequest::new(ClientType::Peer)
+    });
+    request
+        .metadata_mut()
+        .insert("authorization", authorization);
+    let mut stream = client.subscribe(request).await?.into_inner();
+    info!("linked to {}", peer.url);
+    while let Some(mut event) = stream.try_next().await? {
+        if let Some(event::Body::Heartbeat(heartbeat)) = event.body {
+            client.ack_heartbeat(heartbeat).await?;
+            continue;
+        }
+        // Posted here again, keeping the origin and the hops.
+        event.header = Some(Header {
+            client_type: ClientType::Peer.into(),
+        });
+        event.id = Uuid::new_v4().to_string();
+        event.received_at = Some(SystemTime::now().into());
+        event.options = None;
+        event.trace_context.clear();
+        tracing::inject(&mut event.trace_context);
+        if hub.stamp(&mut event).await {
+            hub.publish(ClientType::Peer, &event, DEFAULT_DELIVERY_TIMEOUT)
+                .await;
+        }
+    }
+    anyhow::bail!("the peer closed the subscription")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let peer: Peer = "https://example.com:49252 secret #langdev #rust-*"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            peer,
+            Peer {
+                url: "https://example.com:49252".to_owned(),
+                token: "secret".to_owned(),
+                channels: vec!["#langdev".to_owned(), "#rust-*".to_owned()],
+            }
+        );
+        assert!("https://example.com:49252".parse::<Peer>().is_err());
+        // The token would go in the clear.
+        assert!("http://example.com:49252 secret".parse::<Peer>().is_err());
+        for url in [
+            "http://localhost:49252",
+            "http://127.0.0.1",
+            "http://[::1]:49252",
+        ] {
+            assert!(format!("{} secret", url).parse::<Peer>().is_ok());
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+
+use rendezvous_common::proto::{admin::Route, ClientType, DeliveryStatus};
+
+const BOUNCERS: [ClientType; 3] = [ClientType::Irc, ClientType::Discord, ClientType::Peer];
+
+/// Which bouncers relay events to which.
+///
+/// Only events between bouncers and linked servers are routed; events posted by other clients
+/// reach every bouncer, and subscribers of `Unknown` type see every event.
+#[derive(Debug)]
+pub struct Routes {
+    /// Whether each route is paused.
+    paused: BTreeMap<(ClientType, ClientType), bool>,
+}
+
+impl Default for Routes {
+    /// Every bouncer relays to every other one.
+    fn default() -> Self {
+        let mut paused = BTreeMap::new();
+        for &source in &BOUNCERS {
+            for &destination in &BOUNCERS {
+                if source != destination {
+                    paused.insert((source, destination), false);
+                }
+            }
+        }
+        Routes { paused }
+    }
+}
+
+impl Routes {
+    /// Why an event from `source` can't be relayed to `destination`, if it can't.
+    pub fn check(&self, source: ClientType, destination: ClientType) -> Option<DeliveryStatus> {
+        if source == ClientType::Unknown || destination == ClientType::Unknown {
+            return None;
+        }
+        match self.paused.get(&(source, destination)) {
+            Some(false) => None,
+            Some(true) => Some(DeliveryStatus::Paused),
+            None => Some(DeliveryStatus::NoRoute),
+        }
+    }
+
+    /// Returns the route as it is if it already exists.
+    pub fn add(&mut self, source: ClientType, destination: ClientType) -> Route {
+        let paused = *self.paused.entry((source, destination)).or_insert(false);
+        route(source, destination, paused)
+    }
+
+    pub fn remove(&mut self, source: ClientType, destination: ClientType) -> bool {
+        self.paused.remove(&(source, destination)).is_some()
+    }
+
+    /// Returns `None` if there is no such route.
+    pub fn set_paused(
+        &mut self,
+        source: ClientType,
+        destination: ClientType,
+        paused: bool,
+    ) -> Option<Route> {
+        let state = self.paused.get_mut(&(source, destination))?;
+        *state = paused;
+        Some(route(source, destination, paused))
+    }
+
+    pub fn list(&self) -> Vec<Route> {
+        self.paused
+            .iter()
+            .map(|(&(source, destination), &paused)| route(source, destination, paused))
+            .collect()
+    }
+}
+
+fn route(source: ClientType, destination: ClientType, paused: bool) -> Route {
+    Route {
+        source: source.into(),
+        destination: destination.into(),
+        paused,
+    }
+}
@@ -0,0 +1,449 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic_health::ServingStatus;
+
+use rendezvous_common::{
+    anyhow::{self, Context},
+    futures::{stream, TryFutureExt},
+    proto::{
+        self, admin::admin_service_server::AdminServiceServer,
+        bouncer_service_server::BouncerServiceServer, history_service_server::HistoryServiceServer,
+    },
+    tokio::{
+        self,
+        net::TcpListener,
+        sync::{oneshot, Notify},
+        task::JoinHandle,
+    },
+    tonic::{
+        service::{interceptor::InterceptedService, Interceptor},
+        transport::{Identity, Server, ServerTlsConfig},
+        Request, Status,
+    },
+    tracing::{error, info, warn},
+};
+
+use crate::{
+    admin::{self, AdminServiceImpl},
+    bus::{Bus, LocalBus, RedisBus},
+    config::Config,
+    health,
+    history::HistoryServiceImpl,
+    hub::{self, Hub},
+    peer::{self, Peer},
+    routes::Routes,
+    storage::{self, MemoryStorage, Retention, SqliteStorage, Storage},
+    BouncerServiceImpl,
+};
+
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_MISSED_HEARTBEATS: u32 = 3;
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Looks at each request to the bouncer, history and admin services before it's handled, and
+/// rejects it by returning an error.
+pub type Middleware = Arc<dyn Fn(Request<()>) -> Result<Request<()>, Status> + Send + Sync>;
+
+/// Sets up a server, which [`ServerBuilder::serve`] starts.
+///
+/// Without further settings, the server keeps the history in memory, runs alone, and serves
+/// neither the admin service nor linked servers.
+pub struct ServerBuilder {
+    addrs: Vec<SocketAddr>,
+    name: String,
+    storage: Arc<dyn Storage>,
+    bus: Arc<dyn Bus>,
+    routes: Routes,
+    middleware: Vec<Middleware>,
+    admin_token: Option<String>,
+    peer_token: Option<String>,
+    peers: Vec<Peer>,
+    tls: Option<Identity>,
+    keepalive_interval: Duration,
+    keepalive_timeout: Duration,
+    heartbeat_interval: Duration,
+    max_missed_heartbeats: u32,
+    drain_timeout: Duration,
+    retention: Retention,
+    outbox_max_age: Duration,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            addrs: vec![],
+            name: hub::DEFAULT_NAME.to_owned(),
+            storage: Arc::new(MemoryStorage::default()),
+            bus: Arc::new(LocalBus),
+            routes: Routes::default(),
+            middleware: vec![],
+            admin_token: None,
+            peer_token: None,
+            peers: vec![],
+            tls: None,
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            keepalive_timeout: DEFAULT_KEEPALIVE_TIMEOUT,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            max_missed_heartbeats: DEFAULT_MAX_MISSED_HEARTBEATS,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            retention: Retention::default(),
+            outbox_max_age: hub::DEFAULT_OUTBOX_MAX_AGE,
+        }
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Opens the storage and the bus `config` asks for.
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let storage: Arc<dyn Storage> = match &config.database {
+            Some(path) => Arc::new(SqliteStorage::open(path)?),
+            None => {
+                warn!(
+                    "RENDEZVOUS_DATABASE is not set, keeping only the latest {} events in memory",
+                    MemoryStorage::DEFAULT_MAX_EVENTS
+                );
+                Arc::new(MemoryStorage::default())
+            }
+        };
+        let bus: Arc<dyn Bus> = match &config.redis_url {
+            Some(url) => Arc::new(RedisBus::new(url, format!("{}.events", config.name))?),
+            None => Arc::new(LocalBus),
+        };
+        let tls = match &config.tls {
+            Some((cert, key)) => Some(Identity::from_pem(
+                std::fs::read(cert).with_context(|| format!("failed to read {:?}", cert))?,
+                std::fs::read(key).with_context(|| format!("failed to read {:?}", key))?,
+            )),
+            None => None,
+        };
+        Ok(ServerBuilder {
+            addrs: config.listen.clone(),
+            name: config.name.clone(),
+            storage,
+            bus,
+            admin_token: config.admin_token.clone(),
+            peer_token: config.peer_token.clone(),
+            peers: config.peers.clone(),
+            tls,
+            keepalive_interval: config.keepalive_interval,
+            keepalive_timeout: config.keepalive_timeout,
+            heartbeat_interval: config.heartbeat_interval,
+            max_missed_heartbeats: config.max_missed_heartbeats,
+            drain_timeout: config.drain_timeout,
+            retention: config.retention,
+            outbox_max_age: config.outbox_max_age,
+            ..Default::default()
+        })
+    }
+
+    /// Adds an address to listen on; port `0` picks a free one, which
+    /// [`ServerHandle::local_addrs`] tells.
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Tells this server from the others relaying the same events.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Shares the events with the other servers on `bus`.
+    pub fn bus(mut self, bus: Arc<dyn Bus>) -> Self {
+        self.bus = bus;
+        self
+    }
+
+    /// Which bouncers relay events to which when the server starts.
+    pub fn routes(mut self, routes: Routes) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// Runs `middleware` after the ones added before.
+    pub fn middleware(mut self, middleware: Middleware) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    /// Serves the admin service to the requests which carry `token`.
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Lets the other servers which carry `token` link to this one.
+    pub fn peer_token(mut self, token: impl Into<String>) -> Self {
+        self.peer_token = Some(token.into());
+        self
+    }
+
+    /// Links to `peer` once the server starts.
+    pub fn peer(mut self, peer: Peer) -> Self {
+        self.peers.push(peer);
+        self
+    }
+
+    /// Serves TLS with the certificate chain and the private key of `identity`.
+    pub fn tls(mut self, identity: Identity) -> Self {
+        self.tls = Some(identity);
+        self
+    }
+
+    /// HTTP/2 PING interval, and how long to wait for a response.
+    pub fn keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.keepalive_interval = interval;
+        self.keepalive_timeout = timeout;
+        self
+    }
+
+    /// Subscribers are evicted after missing `max_missed` heartbeats in a row, `0` to never
+    /// evict them.
+    pub fn heartbeats(mut self, interval: Duration, max_missed: u32) -> Self {
+        self.heartbeat_interval = interval;
+        self.max_missed_heartbeats = max_missed;
+        self
+    }
+
+    /// How long to wait for subscribers to receive queued events on shutdown.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Events kept for an offline bouncer longer than this are summarized when it is back.
+    pub fn outbox_max_age(mut self, max_age: Duration) -> Self {
+        self.outbox_max_age = max_age;
+        self
+    }
+
+    /// Binds the addresses and starts serving on them, in the background of the current tokio
+    /// runtime.
+    pub async fn serve(self) -> anyhow::Result<ServerHandle> {
+        if self.addrs.is_empty() {
+            anyhow::bail!("no address to listen on");
+        }
+        // Linked servers would take each other's events for their own coming back around.
+        if (!self.peers.is_empty() || self.peer_token.is_some()) && self.name == hub::DEFAULT_NAME {
+            anyhow::bail!(
+                "a server linked to others needs a name of its own, set RENDEZVOUS_SERVER_NAME"
+            );
+        }
+        for peer in &self.peers {
+            peer::check_url(&peer.url)?;
+        }
+        let mut server = Server::builder();
+        if let Some(identity) = self.tls {
+            server = server.tls_config(ServerTlsConfig::new().identity(identity))?;
+        }
+        let mut listeners = vec![];
+        let mut local_addrs = vec![];
+        for addr in &self.addrs {
+            let listener = TcpListener::bind(addr).await?;
+            local_addrs.push(listener.local_addr()?);
+            listeners.push(TcpListenerStream::new(listener));
+        }
+        let incoming = stream::select_all(listeners);
+
+        let storage = self.storage;
+        let hub = Arc::new(Hub::new(
+            self.name,
+            Arc::clone(&storage),
+            self.bus,
+            self.routes,
+            self.outbox_max_age,
+        ));
+        hub.load_bouncers().await?;
+        let intercept = Intercept {
+            middleware: self.middleware,
+        };
+        let svc = BouncerServiceServer::with_interceptor(
+            BouncerServiceImpl::new(Arc::clone(&hub), self.peer_token),
+            intercept.clone(),
+        );
+        let history_svc = HistoryServiceServer::with_interceptor(
+            HistoryServiceImpl::new(Arc::clone(&storage)),
+            intercept.clone(),
+        );
+        let admin_svc = self.admin_token.map(|token| {
+            InterceptedService::new(
+                AdminServiceServer::with_interceptor(
+                    AdminServiceImpl::new(Arc::clone(&hub), Arc::clone(&storage)),
+                    admin::authorize(token),
+                ),
+                intercept,
+            )
+        });
+        let (mut health_reporter, health_svc) = tonic_health::server::health_reporter();
+        let reflection_svc = tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
+            .build()?;
+
+        let mut tasks = vec![
+            tokio::spawn(
+                Arc::clone(&hub)
+                    .run_heartbeats(self.heartbeat_interval, self.max_missed_heartbeats),
+            ),
+            tokio::spawn(Arc::clone(&hub).run_bus()),
+            tokio::spawn(health::report(
+                health_reporter.clone(),
+                Arc::clone(&hub),
+                Arc::clone(&storage),
+            )),
+        ];
+        for peer in self.peers {
+            tasks.push(tokio::spawn(peer::link(Arc::clone(&hub), peer)));
+        }
+        if !self.retention.is_unlimited() {
+            tasks.push(tokio::spawn(prune_history(
+                Arc::clone(&storage),
+                self.retention,
+            )));
+        }
+
+        let (shutdown, stop) = oneshot::channel();
+        let task = tokio::spawn({
+            let hub = Arc::clone(&hub);
+            let drain_timeout = self.drain_timeout;
+            let stop_serving = Arc::new(Notify::new());
+            let serve = server
+                .http2_keepalive_interval(Some(self.keepalive_interval))
+                .http2_keepalive_timeout(Some(self.keepalive_timeout))
+                .add_service(health_svc)
+                .add_service(reflection_svc)
+                .add_service(svc)
+                .add_service(history_svc)
+                .add_optional_service(admin_svc)
+                .serve_with_incoming_shutdown(incoming, {
+                    let stop_serving = Arc::clone(&stop_serving);
+                    async move { stop_serving.notified().await }
+                })
+                .err_into::<anyhow::Error>();
+            async move {
+                let drain = async {
+                    // Either asked to, or nobody is left to ask.
+                    let _ = stop.await;
+                    // Before the status, so that `health::report` doesn't set it back.
+                    hub.begin_drain();
+                    health::set_status(&mut health_reporter, ServingStatus::NotServing).await;
+                    // The server stops accepting connections and waits for the open ones, which
+                    // end once the hub closes the subscription streams.
+                    stop_serving.notify_one();
+                    Ok(hub.drain(drain_timeout).await)
+                };
+                let result = tokio::try_join!(serve, drain);
+                for task in tasks {
+                    task.abort();
+                }
+                let ((), undelivered) = result?;
+                if undelivered > 0 {
+                    anyhow::bail!("shut down leaving {} events undelivered", undelivered);
+                }
+                Ok(())
+            }
+        });
+        Ok(ServerHandle {
+            local_addrs,
+            hub,
+            shutdown,
+            task: Some(task),
+        })
+    }
+}
+
+/// A running server, which shuts down when this is dropped.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    hub: Arc<Hub>,
+    shutdown: oneshot::Sender<()>,
+    /// Gone once [`ServerHandle::stopped`] has told how the server stopped.
+    task: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl ServerHandle {
+    /// The addresses the server listens on, in the order they were added.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub fn hub(&self) -> &Arc<Hub> {
+        &self.hub
+    }
+
+    /// Stops accepting connections, waits for the subscribers to receive the events queued for
+    /// them, and then for the server to stop.
+    ///
+    /// Fails if the events couldn't all be delivered in time.
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        let _ = self.shutdown.send(());
+        match self.task {
+            Some(task) => task.await?,
+            None => Ok(()),
+        }
+    }
+
+    /// Waits until the server stops by itself, which it only does if it fails.
+    ///
+    /// Only the first call to return tells how; the later ones, and [`ServerHandle::shutdown`],
+    /// return `Ok(())` right away.
+    pub async fn stopped(&mut self) -> anyhow::Result<()> {
+        let result = match &mut self.task {
+            Some(task) => task.await,
+            None => return Ok(()),
+        };
+        self.task = None;
+        result?
+    }
+}
+
+/// Runs the middleware in order.
+#[derive(Clone)]
+struct Intercept {
+    middleware: Vec<Middleware>,
+}
+
+impl Interceptor for Intercept {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        for middleware in &self.middleware {
+            request = middleware(request)?;
+        }
+        Ok(request)
+    }
+}
+
+/// Deletes old events from the history every `PRUNE_INTERVAL`.
+async fn prune_history(storage: Arc<dyn Storage>, retention: Retention) {
+    let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let result =
+            storage::blocking(&storage, move |s| s.prune(&retention, SystemTime::now())).await;
+        match result {
+            Ok(0) => {}
+            Ok(deleted) => info!("deleted {} events from the history", deleted),
+            Err(e) => error!("failed to prune the history: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use rendezvous_common::{
+    anyhow,
+    proto::{ClientType, SubscriptionFilter},
+    record::{Body, Record},
+};
+
+use super::{
+    Cursor, HistoryQuery, Page, Position, Retention, SearchQuery, Storage, StoredEvent,
+    MAX_OUTBOX_EVENTS,
+};
+
+/// Keeps the history until the server stops, up to a number of the latest events.
+#[derive(Debug)]
+pub struct MemoryStorage {
+    inner: Mutex<Inner>,
+}
+
+impl MemoryStorage {
+    /// How many events [`MemoryStorage::default`] keeps at most.
+    pub const DEFAULT_MAX_EVENTS: usize = 100_000;
+
+    /// Drops the oldest events once there are more than `max_events`, whatever the retention.
+    pub fn new(max_events: usize) -> Self {
+        MemoryStorage {
+            inner: Mutex::new(Inner {
+                max_events,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::new(Self::DEFAULT_MAX_EVENTS)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Oldest first.
+    events: VecDeque<(Position, StoredEvent)>,
+    max_events: usize,
+    ids: HashSet<String>,
+    last_seq: u64,
+    /// Oldest first.
+    outboxes: HashMap<ClientType, VecDeque<(u64, Record)>>,
+    last_outbox_seq: u64,
+    bouncers: HashMap<ClientType, SubscriptionFilter>,
+}
+
+impl Inner {
+    fn insert(&mut self, event: &StoredEvent) {
+        self.last_seq += 1;
+        let position = Position::new(self.last_seq, event);
+        // Right at the end, unless the event is imported.
+        let index = self.events.partition_point(|(p, _)| *p < position);
+        self.events.insert(index, (position, event.clone()));
+        self.ids.insert(event.record.id.clone());
+        if self.events.len() > self.max_events {
+            if let Some((_, oldest)) = self.events.pop_front() {
+                self.ids.remove(&oldest.record.id);
+            }
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn store(&self, event: &StoredEvent) -> anyhow::Result<()> {
+        self.inner.lock().unwrap().insert(event);
+        Ok(())
+    }
+
+    fn import(&self, events: &[StoredEvent]) -> anyhow::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut imported = 0;
+        for event in events {
+            if !inner.ids.contains(&event.record.id) {
+                inner.insert(event);
+                imported += 1;
+            }
+        }
+        Ok(imported)
+    }
+
+    fn export(&self, after: Option<Position>, limit: usize) -> anyhow::Result<Page> {
+        let inner = self.inner.lock().unwrap();
+        let mut events: Vec<_> = inner
+            .events
+            .iter()
+            .filter(|(p, _)| after.map(|after| *p > after).unwrap_or(true))
+            .take(limit + 1)
+            .cloned()
+            .collect();
+        let more = events.len() > limit;
+        events.truncate(limit);
+        Ok(Page { events, more })
+    }
+
+    fn history(&self, query: &HistoryQuery) -> anyhow::Result<Page> {
+        let inner = self.inner.lock().unwrap();
+        let matching = inner.events.iter().filter(|(p, e)| {
+            e.record.body.channel() == Some(&query.channel)
+                && query.since.map(|t| e.time >= t).unwrap_or(true)
+                && query.until.map(|t| e.time < t).unwrap_or(true)
+                && match query.cursor {
+                    Cursor::Latest | Cursor::Earliest => true,
+                    Cursor::Before(cursor) => *p < cursor,
+                    Cursor::After(cursor) => *p > cursor,
+                }
+        });
+        let newest_first = matches!(query.cursor, Cursor::Latest | Cursor::Before(_));
+        let mut events: Vec<_> = if newest_first {
+            matching.rev().take(query.limit + 1).cloned().collect()
+        } else {
+            matching.take(query.limit + 1).cloned().collect()
+        };
+        let more = events.len() > query.limit;
+        events.truncate(query.limit);
+        if newest_first {
+            events.reverse();
+        }
+        Ok(Page { events, more })
+    }
+
+    fn channels(&self) -> anyhow::Result<Vec<String>> {
+        let inner = self.inner.lock().unwrap();
+        let channels: BTreeSet<_> = inner
+            .events
+            .iter()
+            .filter_map(|(_, e)| e.record.body.channel())
+            .collect();
+        Ok(channels.into_iter().map(str::to_owned).collect())
+    }
+
+    fn search(&self, query: &SearchQuery) -> anyhow::Result<Page> {
+        let terms: Vec<_> = query.terms.iter().map(|t| t.to_lowercase()).collect();
+        let inner = self.inner.lock().unwrap();
+        let mut events: Vec<_> = inner
+            .events
+            .iter()
+            .rev()
+            .filter(|(p, e)| {
+                let (nickname, channel, content) = match &e.record.body {
+                    Body::MessageCreated {
+                        nickname,
+                        channel,
+                        content,
+                        ..
+                    } => (nickname, channel, content.to_lowercase()),
+                    _ => return false,
+                };
+                query.before.map(|cursor| *p < cursor).unwrap_or(true)
+                    && terms.iter().all(|t| content.contains(t.as_str()))
+                    && matches_any(&query.channels, channel)
+                    && matches_any(&query.nicknames, nickname)
+                    && (query.sources.is_empty() || query.sources.contains(&e.record.source))
+            })
+            .take(query.limit + 1)
+            .cloned()
+            .collect();
+        let more = events.len() > query.limit;
+        events.truncate(query.limit);
+        Ok(Page { events, more })
+    }
+
+    fn prune(&self, retention: &Retention, now: SystemTime) -> anyhow::Result<usize> {
+        let Inner { events, ids, .. } = &mut *self.inner.lock().unwrap();
+        let before = events.len();
+        if let Some(max_age) = retention.max_age {
+            while matches!(events.front(), Some((_, e)) if e.time + max_age < now) {
+                events.pop_front();
+            }
+        }
+        if let Some(max_events) = retention.max_events {
+            let excess = events.len().saturating_sub(max_events as usize);
+            events.drain(..excess);
+        }
+        if events.len() < before {
+            *ids = events.iter().map(|(_, e)| e.record.id.clone()).collect();
+        }
+        Ok(before - events.len())
+    }
+
+    fn position(&self, seq: u64) -> anyhow::Result<Option<Position>> {
+        let inner = self.inner.lock().unwrap();
+        let found = inner.events.iter().find(|(p, _)| p.seq == seq);
+        Ok(found.map(|&(position, _)| position))
+    }
+
+    fn push_outbox(&self, destination: ClientType, record: &Record) -> anyhow::Result<()> {
+        let inner = &mut *self.inner.lock().unwrap();
+        inner.last_outbox_seq += 1;
+        let outbox = inner.outboxes.entry(destination).or_default();
+        outbox.push_back((inner.last_outbox_seq, record.clone()));
+        let excess = outbox.len().saturating_sub(MAX_OUTBOX_EVENTS);
+        outbox.drain(..excess);
+        Ok(())
+    }
+
+    fn outbox(&self, destination: ClientType) -> anyhow::Result<Vec<(u64, Record)>> {
+        let inner = self.inner.lock().unwrap();
+        let outbox = inner.outboxes.get(&destination);
+        Ok(outbox.into_iter().flatten().cloned().collect())
+    }
+
+    fn ack_outbox(&self, destination: ClientType, seq: u64) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(outbox) = inner.outboxes.get_mut(&destination) {
+            outbox.retain(|&(s, _)| s > seq);
+        }
+        Ok(())
+    }
+
+    fn save_bouncer(
+        &self,
+        client_type: ClientType,
+        filter: &SubscriptionFilter,
+    ) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.bouncers.insert(client_type, filter.clone());
+        Ok(())
+    }
+
+    fn bouncers(&self) -> anyhow::Result<Vec<(ClientType, SubscriptionFilter)>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.bouncers.clone().into_iter().collect())
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Case-insensitively, like `COLLATE NOCASE` in SQLite.
+fn matches_any(candidates: &[String], value: &str) -> bool {
+    candidates.is_empty() || candidates.iter().any(|c| c.eq_ignore_ascii_case(value))
+}
@@ -0,0 +1,554 @@
+//! Chat history.
+//!
+//! Messages, renames and membership changes are stored as they are posted; other events are
+//! only relayed. Each stored event gets a sequence number, increasing in the order they were
+//! stored. The history is ordered by the time the events were received and then by sequence
+//! number, so that imported events take their places among the others however late they were
+//! imported.
+
+mod memory;
+mod sqlite;
+
+use std::fmt::{self, Debug, Display};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rendezvous_common::{
+    anyhow,
+    proto::{ClientType, Event, SubscriptionFilter},
+    record::{Body, Record},
+    tokio,
+};
+
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+/// An event as it was when the server received it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredEvent {
+    pub time: SystemTime,
+    pub record: Record,
+}
+
+impl StoredEvent {
+    /// Returns `None` for events which are not kept in the history.
+    pub fn new(time: SystemTime, event: &Event) -> Option<Self> {
+        let record = Record::from_event(event)?;
+        match record.body {
+            Body::MessageCreated { .. }
+            | Body::UserRenamed { .. }
+            | Body::MembershipChanged { .. } => Some(StoredEvent { time, record }),
+            Body::BouncerStatusChanged { .. } | Body::SystemNotice { .. } => None,
+        }
+    }
+
+    pub fn into_event(self) -> Event {
+        let mut event = self.record.into_event();
+        event.received_at = Some(self.time.into());
+        event
+    }
+}
+
+/// Only the latest events are kept for each offline bouncer, however old; the older ones are
+/// summarized when it's back.
+pub const MAX_OUTBOX_EVENTS: usize = 10_000;
+
+/// How long the history goes back. Unlimited by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub max_events: Option<u64>,
+}
+
+impl Retention {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age.is_none() && self.max_events.is_none()
+    }
+}
+
+/// Where an event is in the history, which pages of it are cursored by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    /// Milliseconds since the Unix epoch.
+    pub time: i64,
+    pub seq: u64,
+}
+
+impl Position {
+    pub fn new(seq: u64, event: &StoredEvent) -> Self {
+        Position {
+            time: to_millis(event.time),
+            seq,
+        }
+    }
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.time, self.seq)
+    }
+}
+
+impl FromStr for Position {
+    type Err = anyhow::Error;
+
+    /// The time may be negative, so it's the last `-` which comes before the sequence number.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (time, seq) = s
+            .rsplit_once('-')
+            .ok_or_else(|| anyhow::anyhow!("invalid position: {}", s))?;
+        Ok(Position {
+            time: time.parse()?,
+            seq: seq.parse()?,
+        })
+    }
+}
+
+/// Where a page of history starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cursor {
+    Latest,
+    Earliest,
+    /// The events right before this position.
+    Before(Position),
+    /// The events right after this position.
+    After(Position),
+}
+
+#[derive(Clone, Debug)]
+pub struct HistoryQuery {
+    pub channel: String,
+    /// Inclusive.
+    pub since: Option<SystemTime>,
+    /// Exclusive.
+    pub until: Option<SystemTime>,
+    pub cursor: Cursor,
+    pub limit: usize,
+}
+
+/// Messages which contain every one of `terms` and match every non-empty filter.
+#[derive(Clone, Debug)]
+pub struct SearchQuery {
+    pub terms: Vec<String>,
+    pub channels: Vec<String>,
+    pub nicknames: Vec<String>,
+    pub sources: Vec<ClientType>,
+    pub before: Option<Position>,
+    pub limit: usize,
+}
+
+impl SearchQuery {
+    /// Splits `query` into terms by whitespace.
+    pub fn terms(query: &str) -> Vec<String> {
+        query.split_whitespace().map(str::to_owned).collect()
+    }
+}
+
+/// Events with their positions.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Page {
+    pub events: Vec<(Position, StoredEvent)>,
+    /// Whether there are more events past the page.
+    pub more: bool,
+}
+
+/// Calls may block, so run them out of the async runtime.
+pub trait Storage: Debug + Send + Sync {
+    fn store(&self, event: &StoredEvent) -> anyhow::Result<()>;
+
+    /// Stores the events whose ids are not in the history yet, returning how many were stored.
+    fn import(&self, events: &[StoredEvent]) -> anyhow::Result<usize>;
+
+    /// Every event after `after`, or from the oldest one if not given, oldest first.
+    fn export(&self, after: Option<Position>, limit: usize) -> anyhow::Result<Page>;
+
+    /// Events of a channel, oldest first.
+    fn history(&self, query: &HistoryQuery) -> anyhow::Result<Page>;
+
+    /// Every channel with events in the history.
+    fn channels(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Matching messages, newest first.
+    ///
+    /// Terms match anywhere in the content case-insensitively, even in the middle of a word,
+    /// since Korean words take particles without a space in between.
+    fn search(&self, query: &SearchQuery) -> anyhow::Result<Page>;
+
+    /// Where the event with the sequence number `seq` is, if it's still there. Cursors were
+    /// only the sequence numbers before the history was ordered by time.
+    fn position(&self, seq: u64) -> anyhow::Result<Option<Position>>;
+
+    /// Deletes the events which fall out of `retention` as of `now`, returning how many were
+    /// deleted. The events kept for offline bouncers are left to `MAX_OUTBOX_EVENTS`.
+    fn prune(&self, retention: &Retention, now: SystemTime) -> anyhow::Result<usize>;
+
+    /// Keeps `record` for `destination`, which is offline, until it's sent there, dropping the
+    /// oldest ones beyond `MAX_OUTBOX_EVENTS`.
+    fn push_outbox(&self, destination: ClientType, record: &Record) -> anyhow::Result<()>;
+
+    /// Every record kept for `destination` with its sequence number, oldest first.
+    fn outbox(&self, destination: ClientType) -> anyhow::Result<Vec<(u64, Record)>>;
+
+    /// Deletes the records kept for `destination` up to `seq`, which were sent.
+    fn ack_outbox(&self, destination: ClientType, seq: u64) -> anyhow::Result<()>;
+
+    /// Remembers that `client_type` subscribed with `filter`, so that events are kept for it
+    /// while it's offline even after a restart.
+    fn save_bouncer(
+        &self,
+        client_type: ClientType,
+        filter: &SubscriptionFilter,
+    ) -> anyhow::Result<()>;
+
+    /// Every bouncer type which subscribed before, with the filter it subscribed with last.
+    fn bouncers(&self) -> anyhow::Result<Vec<(ClientType, SubscriptionFilter)>>;
+
+    /// Fails if the storage can't be used at the moment.
+    fn check(&self) -> anyhow::Result<()>;
+}
+
+/// Runs `f` on `storage` out of the async runtime.
+pub async fn blocking<F, T>(storage: &Arc<dyn Storage>, f: F) -> anyhow::Result<T>
+where
+    F: FnOnce(&dyn Storage) -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let storage = Arc::clone(storage);
+    tokio::task::spawn_blocking(move || f(&*storage)).await?
+}
+
+fn to_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+fn from_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(id: &str, time: SystemTime) -> StoredEvent {
+        message_in("#langdev", "foo", id, &format!("message {}", id), time)
+    }
+
+    fn message_in(
+        channel: &str,
+        nickname: &str,
+        id: &str,
+        content: &str,
+        time: SystemTime,
+    ) -> StoredEvent {
+        StoredEvent {
+            time,
+            record: Record {
+                id: id.to_owned(),
+                source: ClientType::Irc,
+                sent_at: None,
+                received_at: None,
+                origin_id: "".to_owned(),
+                hops: vec![],
+                body: Body::MessageCreated {
+                    nickname: nickname.to_owned(),
+                    channel: channel.to_owned(),
+                    content: content.to_owned(),
+                    origin: "".to_owned(),
+                },
+            },
+        }
+    }
+
+    fn storages() -> [Box<dyn Storage>; 2] {
+        [
+            Box::new(MemoryStorage::default()),
+            Box::new(SqliteStorage::open_in_memory().unwrap()),
+        ]
+    }
+
+    fn ids(page: &Page) -> Vec<&str> {
+        page.events
+            .iter()
+            .map(|(_, e)| e.record.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn history() {
+        let now = UNIX_EPOCH + Duration::from_secs(86400 * 365);
+        let minute = Duration::from_secs(60);
+        for storage in storages() {
+            for i in 0..5 {
+                let time = now + minute * i;
+                storage.store(&message(&i.to_string(), time)).unwrap();
+                let other = message_in("#other", "foo", &format!("other{}", i), "", time);
+                storage.store(&other).unwrap();
+            }
+            let mut query = HistoryQuery {
+                channel: "#langdev".to_owned(),
+                since: None,
+                until: None,
+                cursor: Cursor::Latest,
+                limit: 2,
+            };
+            let latest = storage.history(&query).unwrap();
+            assert_eq!(ids(&latest), ["3", "4"]);
+            assert!(latest.more);
+
+            query.cursor = Cursor::Before(latest.events[0].0);
+            let before = storage.history(&query).unwrap();
+            assert_eq!(ids(&before), ["1", "2"]);
+            assert!(before.more);
+
+            query.cursor = Cursor::After(before.events[0].0);
+            query.limit = 10;
+            let after = storage.history(&query).unwrap();
+            assert_eq!(ids(&after), ["2", "3", "4"]);
+            assert!(!after.more);
+
+            query.cursor = Cursor::Latest;
+            query.since = Some(now + minute);
+            query.until = Some(now + minute * 3);
+            assert_eq!(ids(&storage.history(&query).unwrap()), ["1", "2"]);
+
+            let mut channels = storage.channels().unwrap();
+            channels.sort();
+            assert_eq!(channels, ["#langdev", "#other"]);
+        }
+    }
+
+    #[test]
+    fn position() {
+        let before_epoch = Position { time: -5, seq: 3 };
+        assert_eq!(
+            before_epoch.to_string().parse::<Position>().unwrap(),
+            before_epoch
+        );
+        assert!("3".parse::<Position>().is_err());
+
+        let now = UNIX_EPOCH + Duration::from_secs(86400 * 365);
+        for storage in storages() {
+            storage.store(&message("0", now)).unwrap();
+            let query = HistoryQuery {
+                channel: "#langdev".to_owned(),
+                since: None,
+                until: None,
+                cursor: Cursor::Latest,
+                limit: 1,
+            };
+            let (position, _) = storage.history(&query).unwrap().events[0];
+            assert_eq!(storage.position(position.seq).unwrap(), Some(position));
+            assert_eq!(storage.position(position.seq + 1).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn search() {
+        let now = UNIX_EPOCH + Duration::from_secs(86400 * 365);
+        for storage in storages() {
+            let messages = [
+                ("#langdev", "foo", "지난주에 올린 링크를 찾아요"),
+                ("#langdev", "bar", "Rust 링크: https://www.rust-lang.org/"),
+                ("#random", "foo", "rust-lang 100%"),
+                ("#random", "bar", "ÉTÉ AU CAFÉ"),
+            ];
+            for (i, (channel, nickname, content)) in messages.into_iter().enumerate() {
+                let event = message_in(channel, nickname, &i.to_string(), content, now);
+                storage.store(&event).unwrap();
+            }
+            let query = |query: &str| SearchQuery {
+                terms: SearchQuery::terms(query),
+                channels: vec![],
+                nicknames: vec![],
+                sources: vec![],
+                before: None,
+                limit: 10,
+            };
+            let search = |query: &SearchQuery| storage.search(query).unwrap();
+            assert_eq!(ids(&search(&query("링크"))), ["1", "0"]);
+            assert_eq!(ids(&search(&query("RUST-LANG"))), ["2", "1"]);
+            assert_eq!(ids(&search(&query("링크 rust"))), ["1"]);
+            assert_eq!(ids(&search(&query("100%"))), ["2"]);
+            // Beyond ASCII, whether looked up by trigrams or not.
+            assert_eq!(ids(&search(&query("été"))), ["3"]);
+            assert_eq!(ids(&search(&query("é"))), ["3"]);
+            assert_eq!(ids(&search(&query("1_0"))), Vec::<&str>::new());
+            assert_eq!(ids(&search(&query("\"rust"))), Vec::<&str>::new());
+            let by_nickname = SearchQuery {
+                nicknames: vec!["FOO".to_owned()],
+                ..query("")
+            };
+            assert_eq!(ids(&search(&by_nickname)), ["2", "0"]);
+            let by_channel = SearchQuery {
+                channels: vec!["#random".to_owned()],
+                sources: vec![ClientType::Irc],
+                ..query("rust")
+            };
+            assert_eq!(ids(&search(&by_channel)), ["2"]);
+            let paged = SearchQuery {
+                limit: 1,
+                ..query("rust")
+            };
+            let first = search(&paged);
+            assert_eq!(ids(&first), ["2"]);
+            assert!(first.more);
+            let next = SearchQuery {
+                before: Some(first.events[0].0),
+                ..paged
+            };
+            assert_eq!(ids(&search(&next)), ["1"]);
+        }
+    }
+
+    #[test]
+    fn prune() {
+        let now = UNIX_EPOCH + Duration::from_secs(86400 * 365);
+        let hour = Duration::from_secs(3600);
+        for storage in storages() {
+            for (i, age) in [5, 4, 3, 2, 1].into_iter().enumerate() {
+                storage
+                    .store(&message(&i.to_string(), now - hour * age))
+                    .unwrap();
+            }
+            let unlimited = Retention::default();
+            assert_eq!(storage.prune(&unlimited, now).unwrap(), 0);
+            let by_age = Retention {
+                max_age: Some(hour * 3),
+                max_events: None,
+            };
+            assert_eq!(storage.prune(&by_age, now).unwrap(), 2);
+            let by_size = Retention {
+                max_age: None,
+                max_events: Some(1),
+            };
+            assert_eq!(storage.prune(&by_size, now).unwrap(), 2);
+            assert_eq!(storage.prune(&by_size, now).unwrap(), 0);
+            storage.check().unwrap();
+        }
+    }
+
+    #[test]
+    fn memory_cap() {
+        let now = UNIX_EPOCH + Duration::from_secs(86400 * 365);
+        let storage = MemoryStorage::new(2);
+        for i in 0..3 {
+            storage.store(&message(&i.to_string(), now)).unwrap();
+        }
+        assert_eq!(ids(&storage.export(None, 10).unwrap()), ["1", "2"]);
+        // The dropped event is no longer known.
+        assert_eq!(storage.import(&[message("0", now)]).unwrap(), 1);
+    }
+
+    #[test]
+    fn import_export() {
+        let now = UNIX_EPOCH + Duration::from_secs(86400 * 365);
+        let hour = Duration::from_secs(3600);
+        for storage in storages() {
+            storage.store(&message("0", now)).unwrap();
+            let imported = [
+                message("1", now - hour * 2),
+                message("0", now),
+                message("2", now - hour),
+                message("1", now - hour * 2),
+            ];
+            assert_eq!(storage.import(&imported).unwrap(), 2);
+            assert_eq!(storage.import(&imported).unwrap(), 0);
+
+            // Older than the event stored before them, so before it in the history.
+            let first = storage.export(None, 2).unwrap();
+            assert_eq!(ids(&first), ["1", "2"]);
+            assert!(first.more);
+            let rest = storage.export(Some(first.events[1].0), 2).unwrap();
+            assert_eq!(ids(&rest), ["0"]);
+            assert!(!rest.more);
+            let query = HistoryQuery {
+                channel: "#langdev".to_owned(),
+                since: None,
+                until: None,
+                cursor: Cursor::Latest,
+                limit: 1,
+            };
+            assert_eq!(ids(&storage.history(&query).unwrap()), ["0"]);
+            let earliest = HistoryQuery {
+                cursor: Cursor::Earliest,
+                ..query
+            };
+            assert_eq!(ids(&storage.history(&earliest).unwrap()), ["1"]);
+
+            let by_age = Retention {
+                max_age: Some(hour + hour / 2),
+                max_events: None,
+            };
+            assert_eq!(storage.prune(&by_age, now).unwrap(), 1);
+            let by_size = Retention {
+                max_age: None,
+                max_events: Some(1),
+            };
+            assert_eq!(storage.prune(&by_size, now).unwrap(), 1);
+            assert_eq!(ids(&storage.export(None, 10).unwrap()), ["0"]);
+            assert_eq!(storage.import(&[message("1", now)]).unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn outbox() {
+        let now = UNIX_EPOCH + Duration::from_secs(86400 * 365);
+        for storage in storages() {
+            for i in 0..3 {
+                let destination = if i == 1 {
+                    ClientType::Irc
+                } else {
+                    ClientType::Discord
+                };
+                let mut record = message(&i.to_string(), now).record;
+                record.received_at = Some(now.into());
+                storage.push_outbox(destination, &record).unwrap();
+            }
+            let ids = |records: Vec<(u64, Record)>| -> Vec<String> {
+                records.into_iter().map(|(_, r)| r.id).collect()
+            };
+            let kept = storage.outbox(ClientType::Discord).unwrap();
+            let (first, _) = kept[0];
+            assert_eq!(ids(kept), ["0", "2"]);
+            // Kept until they're sent.
+            assert_eq!(
+                ids(storage.outbox(ClientType::Discord).unwrap()),
+                ["0", "2"]
+            );
+            storage.ack_outbox(ClientType::Discord, first).unwrap();
+            assert_eq!(ids(storage.outbox(ClientType::Discord).unwrap()), ["2"]);
+            assert_eq!(ids(storage.outbox(ClientType::Irc).unwrap()), ["1"]);
+
+            // Not pruned with the history, which may go back less than the bouncer was down.
+            let retention = Retention {
+                max_age: Some(Duration::from_secs(60)),
+                max_events: Some(0),
+            };
+            storage
+                .prune(&retention, now + Duration::from_secs(90))
+                .unwrap();
+            assert_eq!(ids(storage.outbox(ClientType::Irc).unwrap()), ["1"]);
+        }
+    }
+
+    #[test]
+    fn bouncers() {
+        for storage in storages() {
+            let filter = SubscriptionFilter {
+                channels: vec!["#a".to_owned()],
+                ..Default::default()
+            };
+            storage
+                .save_bouncer(ClientType::Irc, &Default::default())
+                .unwrap();
+            storage.save_bouncer(ClientType::Irc, &filter).unwrap();
+            assert_eq!(
+                storage.bouncers().unwrap(),
+                [(ClientType::Irc, filter.clone())]
+            );
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension, Row};
+
+use rendezvous_common::{
+    anyhow,
+    prost::Message,
+    proto::{ClientType, SubscriptionFilter},
+    record::{Body, Record},
+    serde_json,
+};
+
+use super::{
+    from_millis, to_millis, Cursor, HistoryQuery, Page, Position, Retention, SearchQuery, Storage,
+    StoredEvent, MAX_OUTBOX_EVENTS,
+};
+
+/// Before and after every position.
+const LAST: Position = Position {
+    time: i64::MAX,
+    seq: i64::MAX as u64,
+};
+const FIRST: Position = Position {
+    time: i64::MIN,
+    seq: 0,
+};
+
+/// Each one brings the schema from the version of its index to the next, as counted by
+/// `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &[
+    // Databases made before the schema was versioned already have these.
+    "
+    CREATE TABLE IF NOT EXISTS events (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        id TEXT NOT NULL UNIQUE,
+        -- Milliseconds since the Unix epoch.
+        time INTEGER NOT NULL,
+        source TEXT NOT NULL,
+        kind INTEGER NOT NULL,
+        channel TEXT,
+        nickname TEXT,
+        content TEXT,
+        -- The whole event as a JSON record.
+        record TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_time ON events (time);
+    CREATE INDEX IF NOT EXISTS events_channel_time ON events (channel, time);
+    ",
+    // Trigrams find words in the middle of Korean phrases, which have no spaces between
+    // words and their particles.
+    "
+    CREATE VIRTUAL TABLE events_fts USING fts5(
+        content, content = 'events', content_rowid = 'seq', tokenize = 'trigram'
+    );
+    INSERT INTO events_fts (events_fts) VALUES ('rebuild');
+    CREATE TRIGGER events_fts_insert AFTER INSERT ON events WHEN new.content IS NOT NULL
+    BEGIN
+        INSERT INTO events_fts (rowid, content) VALUES (new.seq, new.content);
+    END;
+    CREATE TRIGGER events_fts_delete AFTER DELETE ON events WHEN old.content IS NOT NULL
+    BEGIN
+        INSERT INTO events_fts (events_fts, rowid, content)
+        VALUES ('delete', old.seq, old.content);
+    END;
+    ",
+    "
+    CREATE TABLE outbox (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        destination TEXT NOT NULL,
+        -- The whole event as a JSON record.
+        record TEXT NOT NULL
+    );
+    CREATE INDEX outbox_destination ON outbox (destination, seq);
+    ",
+    "
+    -- Milliseconds since the Unix epoch, when the event was received.
+    ALTER TABLE outbox ADD COLUMN time INTEGER NOT NULL DEFAULT 0;
+    UPDATE outbox SET time = CAST(strftime('%s', 'now') AS INTEGER) * 1000;
+    CREATE TABLE bouncers (
+        client_type TEXT PRIMARY KEY,
+        -- The subscription filter in protobuf.
+        filter BLOB NOT NULL
+    );
+    ",
+    "
+    -- The content in lower case, since `LIKE` only ignores the case of ASCII letters. Filled
+    -- in for the existing events by `fold_contents`.
+    ALTER TABLE events ADD COLUMN folded_content TEXT;
+    ",
+];
+
+/// The version which `fold_contents` runs along with.
+const FOLDED_CONTENT_VERSION: usize = 5;
+
+/// Search terms shorter than this can't be looked up by trigrams, and are matched with `LIKE`.
+const MIN_TRIGRAM_TERM: usize = 3;
+
+/// Keeps the history in a SQLite database.
+///
+/// Besides the event as a whole, the columns which it's looked up by are stored on their own.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        Self::init(conn)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(mut conn: Connection) -> anyhow::Result<Self> {
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            anyhow::bail!("the database is from a newer version, {}", version);
+        }
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            if i + 1 == FOLDED_CONTENT_VERSION {
+                fold_contents(&tx)?;
+            }
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+        }
+        Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn store(&self, event: &StoredEvent) -> anyhow::Result<()> {
+        insert(&self.conn.lock().unwrap(), "INSERT", event)?;
+        Ok(())
+    }
+
+    fn import(&self, events: &[StoredEvent]) -> anyhow::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut imported = 0;
+        for event in events {
+            imported += insert(&tx, "INSERT OR IGNORE", event)?;
+        }
+        tx.commit()?;
+        Ok(imported)
+    }
+
+    fn export(&self, after: Option<Position>, limit: usize) -> anyhow::Result<Page> {
+        let after = after.unwrap_or(FIRST);
+        let conn = self.conn.lock().unwrap();
+        let mut events = conn
+            .prepare(
+                "SELECT seq, time, record FROM events WHERE (time, seq) > (?1, ?2)
+                 ORDER BY time, seq LIMIT ?3",
+            )?
+            .query_map(
+                params![after.time, after.seq as i64, limit as i64 + 1],
+                read_row,
+            )?
+            .collect::<Result<Result<Vec<_>, _>, _>>()??;
+        let more = events.len() > limit;
+        events.truncate(limit);
+        Ok(Page { events, more })
+    }
+
+    fn history(&self, query: &HistoryQuery) -> anyhow::Result<Page> {
+        let (before, after, newest_first) = match query.cursor {
+            Cursor::Latest => (LAST, FIRST, true),
+            Cursor::Earliest => (LAST, FIRST, false),
+            Cursor::Before(position) => (position, FIRST, true),
+            Cursor::After(position) => (LAST, position, false),
+        };
+        let order = if newest_first { "DESC" } else { "ASC" };
+        let sql = format!(
+            "SELECT seq, time, record FROM events
+             WHERE channel = ?1 AND time >= ?2 AND time < ?3
+                 AND (time, seq) < (?4, ?5) AND (time, seq) > (?6, ?7)
+             ORDER BY time {0}, seq {0}
+             LIMIT ?8",
+            order
+        );
+        let conn = self.conn.lock().unwrap();
+        let mut events = conn
+            .prepare(&sql)?
+            .query_map(
+                params![
+                    query.channel,
+                    query.since.map(to_millis).unwrap_or(i64::MIN),
+                    query.until.map(to_millis).unwrap_or(i64::MAX),
+                    before.time,
+                    before.seq as i64,
+                    after.time,
+                    after.seq as i64,
+                    query.limit as i64 + 1,
+                ],
+                read_row,
+            )?
+            .collect::<Result<Result<Vec<_>, _>, _>>()??;
+        let more = events.len() > query.limit;
+        events.truncate(query.limit);
+        if newest_first {
+            events.reverse();
+        }
+        Ok(Page { events, more })
+    }
+
+    fn channels(&self) -> anyhow::Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let channels = conn
+            .prepare("SELECT DISTINCT channel FROM events WHERE channel IS NOT NULL")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(channels)
+    }
+
+    fn search(&self, query: &SearchQuery) -> anyhow::Result<Page> {
+        let mut sql = "SELECT seq, time, record FROM events WHERE content IS NOT NULL".to_owned();
+        let mut values = vec![];
+        let (long, short): (Vec<_>, Vec<_>) = query
+            .terms
+            .iter()
+            .partition(|t| t.chars().count() >= MIN_TRIGRAM_TERM);
+        if !long.is_empty() {
+            sql.push_str(" AND seq IN (SELECT rowid FROM events_fts WHERE events_fts MATCH ?)");
+            let phrases: Vec<_> = long
+                .iter()
+                .map(|t| format!("\"{}\"", t.replace('"', "\"\"")))
+                .collect();
+            values.push(Value::Text(phrases.join(" AND ")));
+        }
+        for term in short {
+            sql.push_str(" AND folded_content LIKE ? ESCAPE '\\'");
+            let escaped = term
+                .to_lowercase()
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            values.push(Value::Text(format!("%{}%", escaped)));
+        }
+        let mut any_of = |column: &str, candidates: Vec<Value>| {
+            if !candidates.is_empty() {
+                let placeholders = vec!["?"; candidates.len()].join(", ");
+                sql.push_str(&format!(" AND {} IN ({})", column, placeholders));
+                values.extend(candidates);
+            }
+        };
+        any_of(
+            "channel COLLATE NOCASE",
+            query.channels.iter().cloned().map(Value::Text).collect(),
+        );
+        any_of(
+            "nickname COLLATE NOCASE",
+            query.nicknames.iter().cloned().map(Value::Text).collect(),
+        );
+        any_of(
+            "source",
+            query
+                .sources
+                .iter()
+                .map(|s| Value::Text(s.as_str().to_owned()))
+                .collect(),
+        );
+        if let Some(before) = query.before {
+            sql.push_str(" AND (time, seq) < (?, ?)");
+            values.push(Value::Integer(before.time));
+            values.push(Value::Integer(before.seq as i64));
+        }
+        sql.push_str(" ORDER BY time DESC, seq DESC LIMIT ?");
+        values.push(Value::Integer(query.limit as i64 + 1));
+
+        let conn = self.conn.lock().unwrap();
+        let mut events = conn
+            .prepare(&sql)?
+            .query_map(params_from_iter(values), read_row)?
+            .collect::<Result<Result<Vec<_>, _>, _>>()??;
+        let more = events.len() > query.limit;
+        events.truncate(query.limit);
+        Ok(Page { events, more })
+    }
+
+    fn prune(&self, retention: &Retention, now: SystemTime) -> anyhow::Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let mut deleted = 0;
+        if let Some(max_age) = retention.max_age {
+            let oldest = to_millis(now) - max_age.as_millis() as i64;
+            deleted += conn.execute("DELETE FROM events WHERE time < ?1", params![oldest])?;
+        }
+        if let Some(max_events) = retention.max_events {
+            deleted += conn.execute(
+                "DELETE FROM events WHERE (time, seq) <= (
+                     SELECT time, seq FROM events ORDER BY time DESC, seq DESC LIMIT 1 OFFSET ?1
+                 )",
+                params![max_events as i64],
+            )?;
+        }
+        Ok(deleted)
+    }
+
+    fn position(&self, seq: u64) -> anyhow::Result<Option<Position>> {
+        let conn = self.conn.lock().unwrap();
+        let time = conn
+            .query_row(
+                "SELECT time FROM events WHERE seq = ?1",
+                params![seq as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(time.map(|time| Position { time, seq }))
+    }
+
+    fn push_outbox(&self, destination: ClientType, record: &Record) -> anyhow::Result<()> {
+        let time = record
+            .received_at
+            .map_or_else(SystemTime::now, SystemTime::from);
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO outbox (destination, time, record) VALUES (?1, ?2, ?3)",
+            params![
+                destination.as_str(),
+                to_millis(time),
+                serde_json::to_string(record)?
+            ],
+        )?;
+        tx.execute(
+            "DELETE FROM outbox WHERE destination = ?1 AND seq <= (
+                 SELECT seq FROM outbox WHERE destination = ?1 ORDER BY seq DESC
+                 LIMIT 1 OFFSET ?2
+             )",
+            params![destination.as_str(), MAX_OUTBOX_EVENTS as i64],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn outbox(&self, destination: ClientType) -> anyhow::Result<Vec<(u64, Record)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement =
+            conn.prepare("SELECT seq, record FROM outbox WHERE destination = ?1 ORDER BY seq")?;
+        let rows = statement.query_map(params![destination.as_str()], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?;
+        rows.map(|row| {
+            let (seq, record) = row?;
+            Ok((seq as u64, serde_json::from_str(&record)?))
+        })
+        .collect()
+    }
+
+    fn ack_outbox(&self, destination: ClientType, seq: u64) -> anyhow::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "DELETE FROM outbox WHERE destination = ?1 AND seq <= ?2",
+            params![destination.as_str(), seq as i64],
+        )?;
+        Ok(())
+    }
+
+    fn save_bouncer(
+        &self,
+        client_type: ClientType,
+        filter: &SubscriptionFilter,
+    ) -> anyhow::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO bouncers (client_type, filter) VALUES (?1, ?2)",
+            params![client_type.as_str(), filter.encode_to_vec()],
+        )?;
+        Ok(())
+    }
+
+    fn bouncers(&self) -> anyhow::Result<Vec<(ClientType, SubscriptionFilter)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT client_type, filter FROM bouncers")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?;
+        rows.map(|row| {
+            let (client_type, filter) = row?;
+            Ok((
+                client_type.parse()?,
+                SubscriptionFilter::decode(&filter[..])?,
+            ))
+        })
+        .collect()
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT 1", [], |_| Ok(()))?;
+        Ok(())
+    }
+}
+
+/// Returns how many rows were inserted, which is 0 if `verb` ignores conflicts and the event
+/// is already stored.
+fn insert(conn: &Connection, verb: &str, event: &StoredEvent) -> anyhow::Result<usize> {
+    let record = &event.record;
+    let content = match &record.body {
+        Body::MessageCreated { content, .. } => Some(content),
+        _ => None,
+    };
+    let sql = format!(
+        "{} INTO events (id, time, source, kind, channel, nickname, content, folded_content,
+             record)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        verb
+    );
+    let inserted = conn.prepare_cached(&sql)?.execute(params![
+        record.id,
+        to_millis(event.time),
+        record.source.as_str(),
+        record.body.kind() as i32,
+        record.body.channel(),
+        record.body.nickname(),
+        content,
+        content.map(|c| c.to_lowercase()),
+        serde_json::to_string(record)?,
+    ])?;
+    Ok(inserted)
+}
+
+/// Fills in `folded_content` for the events stored before it was added.
+fn fold_contents(conn: &Connection) -> anyhow::Result<()> {
+    let contents = conn
+        .prepare("SELECT seq, content FROM events WHERE content IS NOT NULL")?
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut update = conn.prepare("UPDATE events SET folded_content = ?1 WHERE seq = ?2")?;
+    for (seq, content) in contents {
+        update.execute(params![content.to_lowercase(), seq])?;
+    }
+    Ok(())
+}
+
+/// Reads `seq, time, record`.
+fn read_row(row: &Row) -> rusqlite::Result<anyhow::Result<(Position, StoredEvent)>> {
+    let seq: i64 = row.get(0)?;
+    let time: i64 = row.get(1)?;
+    let record: String = row.get(2)?;
+    Ok(serde_json::from_str(&record)
+        .map(|record| {
+            let position = Position {
+                time,
+                seq: seq as u64,
+            };
+            let time = from_millis(time);
+            (position, StoredEvent { time, record })
+        })
+        .map_err(Into::into))
+}
@@ -0,0 +1,346 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rendezvous_common::{
+    anyhow,
+    futures::{
+        future::{self, BoxFuture, FutureExt},
+        stream::{self, StreamExt},
+        TryStreamExt,
+    },
+    proto::{
+        bouncer_service_client::BouncerServiceClient, event, ClientType, DeliveryStatus, Event,
+        MessageCreated, SubscribeRequest,
+    },
+    record::Record,
+    tokio::{self, sync::mpsc},
+    tonic::{transport::Channel, Code, Request},
+};
+use rendezvous_server::{
+    bus::{Bus, BusStream},
+    peer::Peer,
+    ServerBuilder, ServerHandle,
+};
+
+async fn connect(server: &ServerHandle) -> BouncerServiceClient<Channel> {
+    let url = format!("http://{}", server.local_addrs()[0]);
+    BouncerServiceClient::connect(url).await.unwrap()
+}
+
+fn message(client_type: ClientType, content: &str) -> Event {
+    Event::new(
+        client_type,
+        event::Body::MessageCreated(MessageCreated {
+            nickname: "a".to_owned(),
+            channel: "#a".to_owned(),
+            content: content.to_owned(),
+            origin: "".to_owned(),
+        }),
+    )
+}
+
+#[tokio::test]
+async fn relay() {
+    let server = ServerBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .serve()
+        .await
+        .unwrap();
+    let mut client = connect(&server).await;
+    let mut events = client
+        .subscribe(SubscribeRequest::new(ClientType::Irc))
+        .await
+        .unwrap()
+        .into_inner();
+
+    // Only the linked servers tell which servers relayed an event.
+    let mut posted = message(ClientType::Discord, "hello");
+    posted.hops.push("elsewhere".to_owned());
+    let result = client.post(posted).await.unwrap().into_inner();
+    assert_eq!(result.deliveries.len(), 1);
+    assert_eq!(result.deliveries[0].destination(), ClientType::Irc);
+    assert_eq!(result.deliveries[0].status(), DeliveryStatus::Queued);
+    let event = events.try_next().await.unwrap().unwrap();
+    assert_eq!(event.hops, ["rendezvous"]);
+    match event.body {
+        Some(event::Body::MessageCreated(m)) => assert_eq!(m.content, "hello"),
+        body => panic!("unexpected event: {:?}", body),
+    }
+
+    server.shutdown().await.unwrap();
+    // The subscription ends with the server.
+    while events.try_next().await.unwrap_or(None).is_some() {}
+}
+
+#[tokio::test]
+async fn peer() {
+    let server = ServerBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .peer_token("secret")
+        .serve()
+        .await;
+    // Linked servers need names of their own.
+    assert!(server.is_err());
+
+    let server = ServerBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .name("a")
+        .peer_token("secret")
+        .serve()
+        .await
+        .unwrap();
+    let mut client = connect(&server).await;
+    let status = client
+        .post(message(ClientType::Peer, "hello"))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+
+    let mut request = Request::new(message(ClientType::Peer, "hello"));
+    request
+        .metadata_mut()
+        .insert("authorization", "Bearer secret".parse().unwrap());
+    let result = client.post(request).await.unwrap().into_inner();
+    assert_eq!(result.deliveries[0].status(), DeliveryStatus::NoRoute);
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn link() {
+    let b = ServerBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .name("b")
+        .peer_token("secret")
+        .serve()
+        .await
+        .unwrap();
+    let a = ServerBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .name("a")
+        .peer(Peer {
+            url: format!("http://{}", b.local_addrs()[0]),
+            token: "secret".to_owned(),
+            channels: vec![],
+        })
+        .serve()
+        .await
+        .unwrap();
+    let mut events = connect(&a)
+        .await
+        .subscribe(SubscribeRequest::new(ClientType::Irc))
+        .await
+        .unwrap()
+        .into_inner();
+    // Until a has subscribed to b.
+    while b.hub().subscriptions().await.is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    connect(&b)
+        .await
+        .post(message(ClientType::Discord, "hello"))
+        .await
+        .unwrap();
+    let event = events.try_next().await.unwrap().unwrap();
+    assert_eq!(event.hops, ["b", "a"]);
+    match event.body {
+        Some(event::Body::MessageCreated(m)) => assert_eq!(m.content, "hello"),
+        body => panic!("unexpected event: {:?}", body),
+    }
+    a.shutdown().await.unwrap();
+    b.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn replace() {
+    let server = ServerBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .serve()
+        .await
+        .unwrap();
+    let mut client = connect(&server).await;
+    let mut old = client
+        .subscribe(SubscribeRequest::new(ClientType::Irc))
+        .await
+        .unwrap()
+        .into_inner();
+    let new = client
+        .subscribe(SubscribeRequest::new(ClientType::Irc))
+        .await
+        .unwrap()
+        .into_inner();
+    let status = old.try_next().await.unwrap_err();
+    assert_eq!(status.code(), Code::Unavailable);
+    assert_eq!(status.message(), "replaced by a new subscription");
+    assert_eq!(server.hub().subscriptions().await.len(), 1);
+
+    // Hanging up drops the subscription.
+    drop(new);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(server.hub().subscriptions().await.is_empty());
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn replay() {
+    let server = ServerBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .serve()
+        .await
+        .unwrap();
+    let mut client = connect(&server).await;
+    let mut irc = client
+        .subscribe(SubscribeRequest::new(ClientType::Irc))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut discord = client
+        .subscribe(SubscribeRequest::new(ClientType::Discord))
+        .await
+        .unwrap()
+        .into_inner();
+
+    // Logged by `rdvctl tail` as the Discord bouncer saw it.
+    let mut posted = message(ClientType::Irc, "hello");
+    posted.origin_id = "irc-1".to_owned();
+    client.post(posted).await.unwrap();
+    let logged = discord.try_next().await.unwrap().unwrap();
+    let record = Record::from_event(&logged).unwrap();
+    assert_eq!(record.origin_id, "irc-1");
+
+    let result = client
+        .post(record.into_replay())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_ne!(result.event_id, logged.id);
+    let mut destinations: Vec<_> = result
+        .deliveries
+        .iter()
+        .map(|d| (d.destination(), d.status()))
+        .collect();
+    destinations.sort();
+    assert_eq!(
+        destinations,
+        [
+            (ClientType::Irc, DeliveryStatus::Queued),
+            (ClientType::Discord, DeliveryStatus::Queued),
+        ]
+    );
+    let replayed = irc.try_next().await.unwrap().unwrap();
+    assert_eq!(replayed.id, result.event_id);
+    match replayed.body {
+        Some(event::Body::MessageCreated(m)) => assert_eq!(m.content, "hello"),
+        body => panic!("unexpected event: {:?}", body),
+    }
+    server.shutdown().await.unwrap();
+}
+
+/// Carries the events sent to it, as if another server published them.
+#[derive(Debug)]
+struct TestBus {
+    incoming: Mutex<Option<mpsc::UnboundedReceiver<Event>>>,
+}
+
+impl Bus for TestBus {
+    fn publish<'a>(&'a self, _event: &'a Event) -> BoxFuture<'a, anyhow::Result<()>> {
+        future::ready(Ok(())).boxed()
+    }
+
+    fn subscribe(&self) -> BusStream {
+        match self.incoming.lock().unwrap().take() {
+            Some(incoming) => stream::unfold(incoming, |mut incoming| async move {
+                let event = incoming.recv().await?;
+                Some((Ok(event), incoming))
+            })
+            .boxed(),
+            None => stream::pending().boxed(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn bus() {
+    let (bus, incoming) = mpsc::unbounded_channel();
+    let server = ServerBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .bus(Arc::new(TestBus {
+            incoming: Mutex::new(Some(incoming)),
+        }))
+        .serve()
+        .await
+        .unwrap();
+    let mut events = connect(&server)
+        .await
+        .subscribe(SubscribeRequest::new(ClientType::Irc))
+        .await
+        .unwrap()
+        .into_inner();
+    while server.hub().subscriptions().await.is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Published twice by another server, as when publishing timed out the first time.
+    let mut shared = message(ClientType::Discord, "hello");
+    shared.id = "1".to_owned();
+    shared.origin_id = "1".to_owned();
+    let mut again = shared.clone();
+    again.id = "2".to_owned();
+    let mut other = message(ClientType::Discord, "bye");
+    other.id = "3".to_owned();
+    other.origin_id = "3".to_owned();
+    for event in [shared, again, other] {
+        bus.send(event).unwrap();
+    }
+    let mut ids = vec![];
+    while ids.len() < 2 {
+        let event = events.try_next().await.unwrap().unwrap();
+        if !matches!(event.body, Some(event::Body::Heartbeat(_))) {
+            ids.push(event.id);
+        }
+    }
+    assert_eq!(ids, ["1", "3"]);
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn tls() {
+    use rendezvous_common::tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
+
+    let identity = Identity::from_pem(
+        include_str!("tls/localhost.crt"),
+        include_str!("tls/localhost.key"),
+    );
+    let server = ServerBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(identity)
+        .serve()
+        .await
+        .unwrap();
+
+    // Over cleartext the server doesn't answer.
+    let url = format!("http://{}", server.local_addrs()[0]);
+    let mut client = BouncerServiceClient::connect(url).await.unwrap();
+    assert!(client.post(message(ClientType::Irc, "a")).await.is_err());
+
+    let url = format!("https://{}", server.local_addrs()[0]);
+    let tls = ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(include_str!("tls/ca.crt")))
+        .domain_name("localhost");
+    let channel = Endpoint::from_shared(url)
+        .unwrap()
+        .tls_config(tls)
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut client = BouncerServiceClient::new(channel);
+    let result = client
+        .post(message(ClientType::Irc, "hello"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(result.deliveries.len(), 1);
+
+    server.shutdown().await.unwrap();
+}